@@ -3,6 +3,7 @@ fn main() {
         .pure()
         .cargo_out_dir("protos_gen/")
         .input("src/protos/perfetto_bpftrace.proto")
+        .input("src/protos/profile.proto")
         .include("src/protos")
         .run_from_script();
 }
@@ -0,0 +1,385 @@
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Perfetto,
+    Folded,
+    Pprof,
+    ChromeJson,
+    Firefox,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FoldedGroupBy {
+    None,
+    ThreadName,
+    Pid,
+    Tid,
+}
+
+// The clock bpftrace's timestamps were taken from e.g. nsecs is monotonic and
+// nsecs(tai) is tai
+#[derive(Clone, Copy, PartialEq)]
+pub enum Clock {
+    Boottime,
+    Monotonic,
+    Realtime,
+    Tai,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Compression {
+    // zlib compressed_packets chunks, what traced writes
+    Deflate,
+}
+
+// How much of the capture --ring-buffer keeps
+#[derive(Clone, Copy, PartialEq)]
+pub enum RingBufferLimit {
+    // Nanoseconds before the latest event
+    Span(u64),
+    // Bytes of serialized packets
+    Size(u64),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Command {
+    // Writes the trace, the default
+    Convert,
+    // `btetto stats`
+    Stats,
+    // `btetto check`
+    Check,
+    // `btetto lint-script`
+    LintScript,
+}
+
+pub struct Options {
+    pub command: Command,
+    pub input_files: Vec<String>,
+    pub output_file: Option<String>,
+    pub format: OutputFormat,
+    pub compress: Option<Compression>,
+    pub folded_group_by: FoldedGroupBy,
+    pub symbol_dirs: Vec<String>,
+    pub kallsyms: Option<String>,
+    pub script: Option<String>,
+    pub clock: Option<Clock>,
+    // Nanoseconds per numeric ts unit
+    pub ts_unit_ns: u64,
+    // Added to every ts after the unit conversion, in nanoseconds
+    pub ts_offset: i64,
+    pub merge_into: Option<String>,
+    pub sequence_per_cpu: bool,
+    pub ring_buffer: Option<RingBufferLimit>,
+    // Event names that trigger a snapshot and the nanoseconds it covers
+    // before and after the event
+    pub trigger_on: Vec<String>,
+    pub trigger_before: u64,
+    pub trigger_after: u64,
+    // Start a new output file after this many nanoseconds of events or bytes
+    // of packets
+    pub rotate_every: Option<u64>,
+    pub rotate_size: Option<u64>,
+    // Filters, see filter.rs. since and until are given like a record's ts.
+    pub pids: Vec<u64>,
+    pub tids: Vec<u64>,
+    pub comm: Option<String>,
+    pub name: Option<String>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    // Keep 1 in sample_every and at most rate_limit per second of each event
+    // name
+    pub sample_every: Option<u64>,
+    pub rate_limit: Option<u64>,
+    pub latency_stats: bool,
+    // Put each input file's custom tracks under a track named after the file
+    pub namespace_tracks: bool,
+}
+
+pub fn parse_args(args: &[String]) -> Options {
+    let mut options = Options {
+        command: Command::Convert,
+        input_files: Vec::new(),
+        output_file: None,
+        format: OutputFormat::Perfetto,
+        compress: None,
+        folded_group_by: FoldedGroupBy::ThreadName,
+        symbol_dirs: Vec::new(),
+        kallsyms: None,
+        script: None,
+        clock: None,
+        ts_unit_ns: 1,
+        ts_offset: 0,
+        merge_into: None,
+        sequence_per_cpu: false,
+        ring_buffer: None,
+        trigger_on: Vec::new(),
+        trigger_before: 10_000_000_000,
+        trigger_after: 10_000_000_000,
+        rotate_every: None,
+        rotate_size: None,
+        pids: Vec::new(),
+        tids: Vec::new(),
+        comm: None,
+        name: None,
+        since: None,
+        until: None,
+        sample_every: None,
+        rate_limit: None,
+        latency_stats: false,
+        namespace_tracks: false,
+    };
+
+    let mut i = 1;
+    match args.get(1).map(String::as_str) {
+        Some("stats") => {
+            options.command = Command::Stats;
+            i += 1;
+        }
+        Some("check") => {
+            options.command = Command::Check;
+            i += 1;
+        }
+        Some("lint-script") => {
+            options.command = Command::LintScript;
+            i += 1;
+        }
+        _ => {}
+    }
+    while i < args.len() {
+        let arg = args[i].as_str();
+        match arg {
+            "-o" | "--output" => {
+                options.output_file = Some(get_value(args, i));
+                i += 1;
+            }
+            "--format" => {
+                options.format = match get_value(args, i).as_str() {
+                    "perfetto" => OutputFormat::Perfetto,
+                    "folded" => OutputFormat::Folded,
+                    "pprof" => OutputFormat::Pprof,
+                    "chrome_json" => OutputFormat::ChromeJson,
+                    "firefox" => OutputFormat::Firefox,
+                    format => panic!("Unknown output format {format}"),
+                };
+                i += 1;
+            }
+            "--compress" => {
+                options.compress = match get_value(args, i).as_str() {
+                    // gzip was the original name, kept as an alias
+                    "deflate" | "gzip" => Some(Compression::Deflate),
+                    compression => panic!("Unknown compression {compression}"),
+                };
+                i += 1;
+            }
+            "--folded-group-by" => {
+                options.folded_group_by = match get_value(args, i).as_str() {
+                    "none" => FoldedGroupBy::None,
+                    "thread_name" => FoldedGroupBy::ThreadName,
+                    "pid" => FoldedGroupBy::Pid,
+                    "tid" => FoldedGroupBy::Tid,
+                    group_by => panic!("Unknown folded group by {group_by}"),
+                };
+                i += 1;
+            }
+            "--symbol-dir" => {
+                options.symbol_dirs.push(get_value(args, i));
+                i += 1;
+            }
+            "--kallsyms" => {
+                options.kallsyms = Some(get_value(args, i));
+                i += 1;
+            }
+            "--clock" => {
+                options.clock = Some(match get_value(args, i).as_str() {
+                    "boottime" => Clock::Boottime,
+                    "monotonic" => Clock::Monotonic,
+                    "realtime" => Clock::Realtime,
+                    "tai" => Clock::Tai,
+                    clock => panic!("Unknown clock {clock}"),
+                });
+                i += 1;
+            }
+            "--ts-unit" => {
+                options.ts_unit_ns = match get_value(args, i).as_str() {
+                    "ns" => 1,
+                    "us" => 1_000,
+                    "ms" => 1_000_000,
+                    "s" => 1_000_000_000,
+                    unit => panic!("Unknown ts unit {unit}"),
+                };
+                i += 1;
+            }
+            "--ts-offset" => {
+                let offset = get_value(args, i);
+                options.ts_offset = offset
+                    .parse()
+                    .unwrap_or_else(|_| panic!("Invalid ts offset {offset}"));
+                i += 1;
+            }
+            "--sequence-per-cpu" => options.sequence_per_cpu = true,
+            "--namespace-tracks" => options.namespace_tracks = true,
+            "--latency-stats" => options.latency_stats = true,
+            "--ring-buffer" => {
+                let limit = get_value(args, i);
+                options.ring_buffer = Some(if limit.ends_with('B') {
+                    RingBufferLimit::Size(parse_size(&limit))
+                } else {
+                    RingBufferLimit::Span(parse_duration(&limit))
+                });
+                i += 1;
+            }
+            "--trigger-on" => {
+                let trigger = get_value(args, i);
+                match trigger.strip_prefix("name=") {
+                    Some(name) => options.trigger_on.push(name.to_string()),
+                    None => panic!("Invalid trigger {trigger}, expecting name=<event name>"),
+                }
+                i += 1;
+            }
+            "--trigger-before" => {
+                options.trigger_before = parse_duration(&get_value(args, i));
+                i += 1;
+            }
+            "--trigger-after" => {
+                options.trigger_after = parse_duration(&get_value(args, i));
+                i += 1;
+            }
+            "--rotate-every" => {
+                options.rotate_every = Some(parse_duration(&get_value(args, i)));
+                i += 1;
+            }
+            "--rotate-size" => {
+                options.rotate_size = Some(parse_size(&get_value(args, i)));
+                i += 1;
+            }
+            "--pid" => {
+                let pid = get_value(args, i);
+                options
+                    .pids
+                    .push(pid.parse().unwrap_or_else(|_| panic!("Invalid pid {pid}")));
+                i += 1;
+            }
+            "--tid" => {
+                let tid = get_value(args, i);
+                options
+                    .tids
+                    .push(tid.parse().unwrap_or_else(|_| panic!("Invalid tid {tid}")));
+                i += 1;
+            }
+            "--comm" => {
+                options.comm = Some(get_value(args, i));
+                i += 1;
+            }
+            "--name" => {
+                options.name = Some(get_value(args, i));
+                i += 1;
+            }
+            "--since" => {
+                options.since = Some(get_value(args, i));
+                i += 1;
+            }
+            "--until" => {
+                options.until = Some(get_value(args, i));
+                i += 1;
+            }
+            "--sample" => {
+                let every = get_value(args, i);
+                match every.parse::<u64>() {
+                    Ok(every) if every > 0 => options.sample_every = Some(every),
+                    _ => panic!("Invalid sample rate {every}, expecting N to keep 1 in N"),
+                }
+                i += 1;
+            }
+            "--rate-limit" => {
+                let limit = get_value(args, i);
+                options.rate_limit = Some(
+                    limit
+                        .parse()
+                        .unwrap_or_else(|_| panic!("Invalid rate limit {limit}")),
+                );
+                i += 1;
+            }
+            "--merge-into" => {
+                options.merge_into = Some(get_value(args, i));
+                i += 1;
+            }
+            "--script" => {
+                options.script = Some(get_value(args, i));
+                i += 1;
+            }
+            _ if arg.starts_with("--") => panic!("Unknown option {arg}"),
+            _ => options.input_files.push(arg.to_string()),
+        }
+        i += 1;
+    }
+
+    // Stats are about the input, not a window of it or another trace
+    if options.command == Command::Stats && options.ring_buffer.is_some() {
+        panic!("--ring-buffer can't be used with btetto stats");
+    }
+    if options.command == Command::Stats && options.merge_into.is_some() {
+        panic!("--merge-into can't be used with btetto stats");
+    }
+    let is_rotating = options.rotate_every.is_some() || options.rotate_size.is_some();
+    if is_rotating && options.merge_into.is_some() {
+        panic!("--merge-into can't be combined with --rotate-every or --rotate-size");
+    }
+    if is_rotating && options.ring_buffer.is_some() {
+        panic!("--ring-buffer can't be combined with --rotate-every or --rotate-size");
+    }
+    // The tai clock only exists in btetto's own clock snapshots, which a
+    // merged trace doesn't get
+    if options.merge_into.is_some() && matches!(options.clock, Some(Clock::Tai)) {
+        panic!("--clock tai can't be combined with --merge-into");
+    }
+    if options.compress.is_some() && options.format != OutputFormat::Perfetto {
+        panic!("--compress only applies to the perfetto output format");
+    }
+
+    options
+}
+
+fn get_value(args: &[String], i: usize) -> String {
+    match args.get(i + 1) {
+        Some(value) => value.clone(),
+        None => panic!("Missing value for option {}", args[i]),
+    }
+}
+
+// e.g. 500ms, 30s, 5m, 1h
+fn parse_duration(duration: &str) -> u64 {
+    let unit_start = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let ns_per_unit: u64 = match &duration[unit_start..] {
+        "ns" => 1,
+        "us" => 1_000,
+        "ms" => 1_000_000,
+        "s" => 1_000_000_000,
+        "m" => 60 * 1_000_000_000,
+        "h" => 60 * 60 * 1_000_000_000,
+        _ => panic!("Invalid duration {duration}, expecting e.g. 30s, 5m or 1h"),
+    };
+    match duration[..unit_start].parse::<u64>() {
+        Ok(value) => value * ns_per_unit,
+        Err(_) => panic!("Invalid duration {duration}, expecting e.g. 30s, 5m or 1h"),
+    }
+}
+
+// e.g. 512KB, 100MB, 1GB (powers of 1024)
+fn parse_size(size: &str) -> u64 {
+    let unit_start = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let bytes_per_unit: u64 = match &size[unit_start..] {
+        "B" => 1,
+        "KB" => 1 << 10,
+        "MB" => 1 << 20,
+        "GB" => 1 << 30,
+        _ => panic!("Invalid size {size}, expecting e.g. 512KB, 100MB or 1GB"),
+    };
+    match size[..unit_start].parse::<u64>() {
+        Ok(value) => value * bytes_per_unit,
+        Err(_) => panic!("Invalid size {size}, expecting e.g. 512KB, 100MB or 1GB"),
+    }
+}
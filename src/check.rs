@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+use std::fs;
+
+use serde_json::Value;
+
+use crate::util;
+
+// `btetto check`: lints recorded `-f json` output against what the conversion
+// expects, reporting every violation with its line number rather than
+// panicking at the first one. Returns the number of violations.
+pub fn check_files(filenames: &[String]) -> usize {
+    let mut num_violations = 0;
+    for filename in filenames {
+        let Ok(bytes) = fs::read(filename) else {
+            panic!("Could not read file {filename}");
+        };
+        let mut num_file_violations = 0;
+        // Split by hand so a line that isn't UTF-8 is reported rather than
+        // ending the file early
+        for (i, line) in bytes.split(|&b| b == b'\n').enumerate() {
+            let violations = match std::str::from_utf8(line) {
+                Ok(line) if line.trim().is_empty() => continue,
+                Ok(line) => match serde_json::from_str::<Value>(line) {
+                    Ok(json_line) => check_output(&json_line),
+                    Err(e) => vec![format!("not a JSON record: {e}")],
+                },
+                Err(e) => vec![format!("not UTF-8: {e}")],
+            };
+            for violation in &violations {
+                println!("{filename}:{}: {violation}", i + 1);
+            }
+            num_file_violations += violations.len();
+        }
+        if num_file_violations == 0 {
+            println!("{filename}: ok");
+        }
+        num_violations += num_file_violations;
+    }
+    num_violations
+}
+
+fn check_output(json_line: &Value) -> Vec<String> {
+    if json_line["type"] != "value" {
+        return Vec::new();
+    }
+    let Some(data) = json_line["data"].as_array() else {
+        return vec!["value data must be a tuple".to_string()];
+    };
+    match data.first() {
+        Some(Value::String(data_type)) => match data_type.as_str() {
+            "track_event" => with_fields(data, check_track_event),
+            "call_stack" => with_fields(data, check_call_stack_sample),
+            // e.g. print(("trigger", "oom_kill")) and print(("stdout", "..."))
+            "trigger" | "stdout" => match data.as_slice() {
+                [_, Value::String(_)] => Vec::new(),
+                _ => vec![format!("{data_type} expects a single string")],
+            },
+            _ => vec![format!(
+                "unknown record type {data_type}, expecting one of track_event, call_stack, trigger, stdout"
+            )],
+        },
+        Some(_) => vec!["the first field is not a valid trace data type".to_string()],
+        None => Vec::new(),
+    }
+}
+
+// Checks the (key, value) tuples after the record type, then the record
+fn with_fields(data: &[Value], check: fn(&HashMap<&str, Value>) -> Vec<String>) -> Vec<String> {
+    let mut violations = Vec::new();
+    let mut event = HashMap::new();
+    for pair in &data[1..] {
+        match pair.as_array().map(|p| p.as_slice()) {
+            Some([Value::String(key), value]) => {
+                // Null stands for a run time value in the shared checks
+                if value.is_null() {
+                    violations.push(format!("{key} must not be null"));
+                }
+                event.insert(key.as_str(), value.clone());
+            }
+            _ => violations.push(format!("expecting key/value tuples. Found {pair}")),
+        }
+    }
+    violations.extend(check(&event));
+    violations
+}
+
+// Shared with `btetto lint-script`, which passes the values it can only know
+// at run time, like nsecs or comm, as null. Those are only checked for being
+// there.
+pub(crate) fn check_track_event(event: &HashMap<&str, Value>) -> Vec<String> {
+    let mut violations = util::track_event_violations(event);
+    check_common_fields(event, &mut violations);
+
+    if known(event, "name").is_some_and(|name| !name.is_string()) {
+        violations.push("name must be a string".to_string());
+    }
+    for key in ["track", "track_parent", "flow_id"] {
+        if known(event, key).is_some_and(|v| !v.is_string() && v.as_u64().is_none()) {
+            violations.push(format!("{key} must be a string or a number"));
+        }
+    }
+    if event.contains_key("track_parent") && !event.contains_key("track") {
+        violations.push("track_parent without a track".to_string());
+    }
+    let has_thread = event.contains_key("pid") && event.contains_key("tid");
+    if !event.contains_key("track") && !has_thread {
+        violations.push("track event must have either a pid and tid or a track".to_string());
+    }
+
+    let is_counter = event.get("type").is_some_and(|t| t == "COUNTER");
+    if is_counter {
+        match event.get("counter_value") {
+            None => violations.push("COUNTER without a counter_value".to_string()),
+            Some(value) if !value.is_null() && value.as_i64().is_none() => {
+                violations.push("counter_value must be an integer".to_string())
+            }
+            Some(_) => {}
+        }
+        if !event.contains_key("track") {
+            violations.push("COUNTER must be on a track".to_string());
+        }
+    }
+    if let Some(unit) = known(event, "unit") {
+        match unit.as_str() {
+            Some(unit) if util::COUNTER_UNITS.contains(&unit) => {}
+            _ if !is_counter => {}
+            // Strings without their JSON quotes, like the log level below
+            name => violations.push(format!(
+                "unknown unit {}, expecting one of {}",
+                name.map_or(unit.to_string(), str::to_string),
+                util::COUNTER_UNITS.join(", ")
+            )),
+        }
+    }
+
+    if let Some(log) = known(event, "log") {
+        match log.as_array().map(|l| l.as_slice()) {
+            Some([level, message]) if [level, message].iter().all(|v| v.is_string() || v.is_null()) => {
+                if let Some(level) = level.as_str().filter(|level| !util::LOG_LEVELS.contains(level)) {
+                    violations.push(format!(
+                        "unknown log level {level}, expecting one of {}",
+                        util::LOG_LEVELS.join(", ")
+                    ));
+                }
+            }
+            _ => violations.push(
+                "log must be a (level, message) tuple e.g. (\"log\", (\"WARN\", \"my log message\"))"
+                    .to_string(),
+            ),
+        }
+    }
+    violations
+}
+
+pub(crate) fn check_call_stack_sample(event: &HashMap<&str, Value>) -> Vec<String> {
+    let mut violations = util::call_stack_sample_violations(event);
+    check_common_fields(event, &mut violations);
+    for key in ["ustack", "kstack"] {
+        if known(event, key).is_some_and(|stack| !stack.is_string()) {
+            violations.push(format!("{key} must be a string"));
+        }
+    }
+    violations
+}
+
+// Fields track events and call stack samples share
+fn check_common_fields(event: &HashMap<&str, Value>, violations: &mut Vec<String>) {
+    if let Some(ts) = known(event, "ts") {
+        let is_valid = match ts.as_str() {
+            Some(ts) => ts.parse::<i64>().is_ok() || util::parse_time_str(ts).is_some(),
+            None => ts.as_u64().is_some() || ts.as_f64().is_some_and(|ts| ts >= 0.0),
+        };
+        if !is_valid {
+            violations.push(format!("ts must be a number or a time string. Found {ts}"));
+        }
+    }
+    for key in ["pid", "tid"] {
+        if known(event, key).is_some_and(|v| v.as_u64().is_none()) {
+            violations.push(format!("{key} must be a non-negative integer"));
+        }
+    }
+    if known(event, "thread_name").is_some_and(|name| !name.is_string()) {
+        violations.push("thread_name must be a string".to_string());
+    }
+}
+
+// The value of key, unless it is only known at run time
+fn known<'a>(event: &'a HashMap<&str, Value>, key: &str) -> Option<&'a Value> {
+    event.get(key).filter(|value| !value.is_null())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    fn check(data: Value) -> Vec<String> {
+        check_output(&json!({"type": "value", "data": data}))
+    }
+
+    #[test]
+    fn valid_records_have_no_violations() {
+        let slice = json!([
+            "track_event",
+            ["name", "a"],
+            ["type", "BEGIN"],
+            ["ts", 1],
+            ["pid", 1],
+            ["tid", 2]
+        ]);
+        assert!(check(slice).is_empty());
+        let counter = json!([
+            "track_event",
+            ["name", "c"],
+            ["type", "COUNTER"],
+            ["ts", "13:45:12.5"],
+            ["track", "t"],
+            ["counter_value", 5],
+            ["unit", "count"]
+        ]);
+        assert!(check(counter).is_empty());
+        let sample = json!([
+            "call_stack",
+            ["ts", 1],
+            ["pid", 1],
+            ["tid", 2],
+            ["kstack", "f\n"]
+        ]);
+        assert!(check(sample).is_empty());
+        assert!(check(json!(["trigger", "oom_kill"])).is_empty());
+        assert!(check(json!(["stdout", "hello"])).is_empty());
+        assert!(check(json!([])).is_empty());
+        // Only print() output is checked
+        assert!(check_output(&json!({"type": "map", "data": {}})).is_empty());
+    }
+
+    #[test]
+    fn unknown_record_types_are_reported() {
+        assert_eq!(
+            check(json!(["trak_event", ["name", "a"]])),
+            ["unknown record type trak_event, expecting one of track_event, call_stack, trigger, stdout"]
+        );
+        assert_eq!(
+            check(json!([1, 2])),
+            ["the first field is not a valid trace data type"]
+        );
+    }
+
+    #[test]
+    fn trigger_and_stdout_expect_a_string() {
+        assert_eq!(
+            check(json!(["trigger"])),
+            ["trigger expects a single string"]
+        );
+        assert_eq!(
+            check(json!(["trigger", ["name", "x"]])),
+            ["trigger expects a single string"]
+        );
+        assert_eq!(
+            check(json!(["stdout", "a", "b"])),
+            ["stdout expects a single string"]
+        );
+    }
+
+    #[test]
+    fn bad_track_events_are_reported() {
+        let counter = json!([
+            "track_event",
+            ["name", "c"],
+            ["type", "COUNTER"],
+            ["ts", 1],
+            ["pid", 1],
+            ["tid", 2],
+            ["unit", "meters"]
+        ]);
+        let violations = check(counter);
+        assert!(violations.contains(&"COUNTER without a counter_value".to_string()));
+        assert!(violations.contains(&"COUNTER must be on a track".to_string()));
+        assert!(violations.contains(
+            &"unknown unit meters, expecting one of unspecified, count, sized_bytes, time_ns"
+                .to_string()
+        ));
+        let counter = json!([
+            "track_event",
+            ["name", "c"],
+            ["type", "COUNTER"],
+            ["ts", 1],
+            ["track", "t"],
+            ["counter_value", 1],
+            ["unit", 5]
+        ]);
+        assert_eq!(
+            check(counter),
+            ["unknown unit 5, expecting one of unspecified, count, sized_bytes, time_ns"]
+        );
+
+        let slice = json!([
+            "track_event",
+            ["name", "a"],
+            ["type", "BEGIN"],
+            ["ts", -1],
+            ["track_parent", "p"],
+            ["log", "oops"],
+            "stray"
+        ]);
+        let violations = check(slice);
+        assert!(violations.contains(&"expecting key/value tuples. Found \"stray\"".to_string()));
+        assert!(violations.contains(&"ts must be a number or a time string. Found -1".to_string()));
+        assert!(violations.contains(&"track_parent without a track".to_string()));
+        assert!(violations
+            .contains(&"track event must have either a pid and tid or a track".to_string()));
+        assert!(violations
+            .iter()
+            .any(|v| v.starts_with("log must be a (level, message) tuple")));
+    }
+
+    #[test]
+    fn nulls_are_reported() {
+        let slice = json!([
+            "track_event",
+            ["name", "a"],
+            ["type", null],
+            ["ts", 1],
+            ["track", null]
+        ]);
+        assert_eq!(
+            check(slice),
+            ["type must not be null", "track must not be null"]
+        );
+    }
+
+    #[test]
+    fn bad_call_stack_samples_are_reported() {
+        let sample = json!([
+            "call_stack",
+            ["ts", 1],
+            ["pid", "1"],
+            ["tid", 2],
+            ["ustack", 3]
+        ]);
+        assert_eq!(
+            check(sample),
+            [
+                "pid must be a non-negative integer",
+                "ustack must be a string"
+            ]
+        );
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::fs;
+
+use serde_json::{json, Map, Value};
+
+use crate::interned::SequenceState;
+use crate::protos::protos_gen::perfetto_bpftrace::{trace_packet, track_event, Trace};
+use crate::util;
+
+struct TrackInfo {
+    pid: u64,
+    tid: u64,
+    name: String,
+}
+
+struct Event {
+    ts: u64,
+    track_uuid: u64,
+    type_: track_event::Type,
+    name: String,
+    args: Map<String, Value>,
+    counter_value: i64,
+    flow_ids: Vec<u64>,
+}
+
+// Writes the track events in the trace in the legacy Chrome JSON trace event
+// format (https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU).
+// Matched BEGIN/END pairs become complete ("X") events. Returns the number of
+// trace events written.
+pub fn write_chrome_json(trace: &Trace, filename: &str) -> usize {
+    let mut sequence_state = SequenceState::default();
+    let mut tracks: HashMap<u64, TrackInfo> = HashMap::new();
+    // Each top level custom track becomes a pseudo process and each custom
+    // track a pseudo thread
+    let mut next_track_id = util::TRACK_ID_BASE;
+    let mut events: Vec<Value> = Vec::new();
+    // Indexes of the open BEGIN events per track
+    let mut open_slices: HashMap<u64, Vec<usize>> = HashMap::new();
+    // Indexes of the flow events per flow id
+    let mut flows: HashMap<u64, Vec<usize>> = HashMap::new();
+
+    let mut track_events: Vec<Event> = Vec::new();
+
+    // Names and args are resolved in packet order, where the interned data is
+    // valid, before the events are sorted by timestamp for slice matching
+    for packet in &trace.packet {
+        let interned = sequence_state.update(packet);
+        match &packet.data {
+            Some(trace_packet::Data::TrackDescriptor(track_descriptor)) => {
+                let uuid = track_descriptor.uuid();
+                let info = if let Some(thread) = track_descriptor.thread.as_ref() {
+                    TrackInfo {
+                        pid: thread.pid() as u64,
+                        tid: thread.tid() as u64,
+                        name: thread.thread_name().to_string(),
+                    }
+                } else {
+                    let name = track_descriptor.name().to_string();
+                    let track_id = next_track_id;
+                    next_track_id += 1;
+                    let pid = match tracks.get(&track_descriptor.parent_uuid()) {
+                        Some(parent) => parent.pid,
+                        None => {
+                            events.push(json!({
+                                "ph": "M",
+                                "name": "process_name",
+                                "pid": track_id,
+                                "args": { "name": name },
+                            }));
+                            track_id
+                        }
+                    };
+                    TrackInfo {
+                        pid,
+                        tid: track_id,
+                        name,
+                    }
+                };
+                events.push(json!({
+                    "ph": "M",
+                    "name": "thread_name",
+                    "pid": info.pid,
+                    "tid": info.tid,
+                    "args": { "name": info.name },
+                }));
+                tracks.insert(uuid, info);
+            }
+            Some(trace_packet::Data::TrackEvent(track_event)) => {
+                track_events.push(Event {
+                    ts: packet.timestamp(),
+                    track_uuid: track_event.track_uuid(),
+                    type_: track_event.type_(),
+                    name: interned.get_event_name(track_event),
+                    args: interned.get_event_args(track_event),
+                    counter_value: track_event.counter_value(),
+                    flow_ids: track_event.flow_ids.clone(),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    track_events.sort_by_key(|e| e.ts);
+
+    for event in track_events {
+        let Some(track) = tracks.get(&event.track_uuid) else {
+            continue;
+        };
+        let ts = event.ts as f64 / 1000.0;
+
+        match event.type_ {
+            track_event::Type::TYPE_SLICE_BEGIN => {
+                open_slices
+                    .entry(event.track_uuid)
+                    .or_default()
+                    .push(events.len());
+                events.push(json!({
+                    "ph": "B",
+                    "name": event.name,
+                    "ts": ts,
+                    "pid": track.pid,
+                    "tid": track.tid,
+                    "args": event.args,
+                }));
+            }
+            track_event::Type::TYPE_SLICE_END => {
+                let begin = open_slices.get_mut(&event.track_uuid).and_then(|s| s.pop());
+                match begin {
+                    Some(index) => {
+                        let begin_event = &mut events[index];
+                        let begin_ts = begin_event["ts"].as_f64().unwrap();
+                        begin_event["ph"] = json!("X");
+                        begin_event["dur"] = json!(ts - begin_ts);
+                        merge_args(begin_event, event.args);
+                    }
+                    None => events.push(json!({
+                        "ph": "E",
+                        "name": event.name,
+                        "ts": ts,
+                        "pid": track.pid,
+                        "tid": track.tid,
+                        "args": event.args,
+                    })),
+                }
+            }
+            track_event::Type::TYPE_INSTANT => {
+                events.push(json!({
+                    "ph": "i",
+                    "s": "t",
+                    "name": event.name,
+                    "ts": ts,
+                    "pid": track.pid,
+                    "tid": track.tid,
+                    "args": event.args,
+                }));
+            }
+            track_event::Type::TYPE_COUNTER => {
+                events.push(json!({
+                    "ph": "C",
+                    "name": track.name,
+                    "ts": ts,
+                    "pid": track.pid,
+                    "args": { "value": event.counter_value },
+                }));
+            }
+            _ => {}
+        }
+
+        for flow_id in event.flow_ids {
+            let steps = flows.entry(flow_id).or_default();
+            steps.push(events.len());
+            events.push(json!({
+                "ph": if steps.len() == 1 { "s" } else { "t" },
+                "name": "flow",
+                "cat": "flow",
+                "id": flow_id,
+                "ts": ts,
+                "pid": track.pid,
+                "tid": track.tid,
+            }));
+        }
+    }
+
+    // The last event of each flow terminates it
+    for steps in flows.values() {
+        if steps.len() > 1 {
+            let last = &mut events[*steps.last().unwrap()];
+            last["ph"] = json!("f");
+            last["bp"] = json!("e");
+        }
+    }
+
+    let num_events = events.len();
+    let out = json!({
+        "traceEvents": events,
+        "displayTimeUnit": "ns",
+    });
+    fs::write(filename, out.to_string()).expect("Could not write Chrome JSON trace file");
+
+    num_events
+}
+
+fn merge_args(event: &mut Value, args: Map<String, Value>) {
+    if let Some(event_args) = event["args"].as_object_mut() {
+        event_args.extend(args);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{env, process};
+
+    use crate::tests::convert;
+
+    #[test]
+    fn chrome_json_export() {
+        let lines = [
+            json!({"type": "value", "data": ["track_event", ["name", "work"], ["type", "BEGIN"],
+                ["ts", 1_000], ["pid", 1], ["tid", 2], ["thread_name", "worker"]]}),
+            json!({"type": "value", "data": ["track_event", ["name", "work"], ["type", "END"],
+                ["ts", 3_500], ["pid", 1], ["tid", 2]]}),
+            json!({"type": "value", "data": ["track_event", ["name", "enqueue"], ["type", "INSTANT"],
+                ["ts", 2_000], ["track", "queue"], ["track_parent", "io"]]}),
+        ];
+        let trace = convert(&lines, &[]);
+        let output_file = env::temp_dir().join(format!("btetto-test-{}.json", process::id()));
+        let output_file = output_file.to_str().unwrap();
+        write_chrome_json(&trace, output_file);
+        let json: Value = serde_json::from_str(&fs::read_to_string(output_file).unwrap()).unwrap();
+        fs::remove_file(output_file).unwrap();
+
+        let events = json["traceEvents"].as_array().unwrap();
+        let find = |name: &str| events.iter().find(|e| e["name"] == name).unwrap();
+        // The BEGIN/END pair is one complete event, in microseconds
+        let work = find("work");
+        assert_eq!(work["ph"], "X");
+        assert_eq!(work["ts"], 1.0);
+        assert_eq!(work["dur"], 2.5);
+        assert_eq!((&work["pid"], &work["tid"]), (&json!(1), &json!(2)));
+
+        // Custom tracks get a pseudo pid and tid that fit in 32 bits
+        let enqueue = find("enqueue");
+        assert_eq!(enqueue["ph"], "i");
+        for id in [&enqueue["pid"], &enqueue["tid"]] {
+            let id = id.as_u64().unwrap();
+            assert!(id > 2 && id <= i32::MAX as u64);
+        }
+    }
+}
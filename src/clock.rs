@@ -0,0 +1,75 @@
+use crate::args::Clock;
+use crate::protos::protos_gen::perfetto_bpftrace::{clock_snapshot, BuiltinClock, ClockSnapshot};
+
+// Perfetto has no builtin TAI clock so it's a sequence scoped user clock
+// (ids 64-127), only valid on the sequence that emitted the snapshot
+const TAI_CLOCK_ID: u32 = 64;
+
+pub fn get_clock_id(clock: Clock) -> u32 {
+    match clock {
+        Clock::Boottime => BuiltinClock::BUILTIN_CLOCK_BOOTTIME as u32,
+        Clock::Monotonic => BuiltinClock::BUILTIN_CLOCK_MONOTONIC as u32,
+        Clock::Realtime => BuiltinClock::BUILTIN_CLOCK_REALTIME as u32,
+        Clock::Tai => TAI_CLOCK_ID,
+    }
+}
+
+fn read_clock(clock: Clock) -> u64 {
+    let clock_id = match clock {
+        Clock::Boottime => libc::CLOCK_BOOTTIME,
+        Clock::Monotonic => libc::CLOCK_MONOTONIC,
+        Clock::Realtime => libc::CLOCK_REALTIME,
+        Clock::Tai => libc::CLOCK_TAI,
+    };
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(clock_id, &mut ts);
+    }
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+// Reads every clock bpftrace can timestamp with, back to back, so the trace
+// processor can convert between them and line the trace up with others
+// taken on the same machine
+pub fn take_snapshot() -> ClockSnapshot {
+    let mut clock_snapshot = ClockSnapshot::new();
+    for clock in [
+        Clock::Boottime,
+        Clock::Monotonic,
+        Clock::Realtime,
+        Clock::Tai,
+    ] {
+        let mut snapshot_clock = clock_snapshot::Clock::new();
+        snapshot_clock.clock_id = Some(get_clock_id(clock));
+        snapshot_clock.timestamp = Some(read_clock(clock));
+        clock_snapshot.clocks.push(snapshot_clock);
+    }
+    clock_snapshot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_ids() {
+        assert_eq!(get_clock_id(Clock::Boottime), 6);
+        assert_eq!(get_clock_id(Clock::Monotonic), 3);
+        assert_eq!(get_clock_id(Clock::Realtime), 1);
+        // Sequence scoped clocks are 64-127
+        assert!((64..128).contains(&get_clock_id(Clock::Tai)));
+    }
+
+    #[test]
+    fn snapshot_has_every_clock() {
+        let snapshot = take_snapshot();
+        let clock_ids: Vec<u32> = snapshot.clocks.iter().map(|c| c.clock_id()).collect();
+        assert_eq!(clock_ids, [6, 3, 1, TAI_CLOCK_ID]);
+        assert!(snapshot.clocks.iter().all(|c| c.timestamp() > 0));
+        // Realtime counts from 1970, boottime from boot
+        assert!(snapshot.clocks[2].timestamp() > snapshot.clocks[0].timestamp());
+    }
+}
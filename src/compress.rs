@@ -0,0 +1,155 @@
+use std::io::{Read, Write};
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use protobuf::Message;
+
+use crate::args::Compression;
+use crate::protos::protos_gen::perfetto_bpftrace::{trace_packet, Trace, TracePacket};
+
+// Uncompressed size of the packets that go in one compressed_packets chunk,
+// about what traced uses
+const CHUNK_SIZE: u64 = 512 * 1024;
+
+// Serializes the trace for writing to a file. deflate packs the packets into
+// compressed_packets chunks, which is still a trace Perfetto loads as is.
+pub fn write_trace(trace: Trace, compression: Option<Compression>) -> Vec<u8> {
+    match compression {
+        None => trace.write_to_bytes().unwrap(),
+        Some(Compression::Deflate) => compress_packets(trace).write_to_bytes().unwrap(),
+    }
+}
+
+fn compress_packets(trace: Trace) -> Trace {
+    let mut compressed_trace = Trace::new();
+    let mut chunk = Trace::new();
+    let mut chunk_size = 0;
+
+    for packet in trace.packet {
+        chunk_size += packet.compute_size();
+        chunk.packet.push(packet);
+        if chunk_size >= CHUNK_SIZE {
+            compressed_trace.packet.push(compress_chunk(&chunk));
+            chunk = Trace::new();
+            chunk_size = 0;
+        }
+    }
+    if !chunk.packet.is_empty() {
+        compressed_trace.packet.push(compress_chunk(&chunk));
+    }
+
+    compressed_trace
+}
+
+// The chunk is the packets serialized as a Trace i.e. repeated field 1
+fn compress_chunk(chunk: &Trace) -> TracePacket {
+    let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder
+        .write_all(&chunk.write_to_bytes().unwrap())
+        .expect("Could not compress packets");
+
+    let mut packet = TracePacket::new();
+    packet.data = Some(trace_packet::Data::CompressedPackets(
+        encoder.finish().expect("Could not compress packets"),
+    ));
+    packet
+}
+
+// Expands compressed_packets chunks in place, e.g. from a trace recorded by
+// traced with compression enabled
+pub fn decompress_packets(trace: Trace) -> Trace {
+    let mut decompressed_trace = Trace::new();
+    for packet in trace.packet {
+        match &packet.data {
+            Some(trace_packet::Data::CompressedPackets(bytes)) => {
+                let mut chunk_bytes = Vec::new();
+                ZlibDecoder::new(bytes.as_slice())
+                    .read_to_end(&mut chunk_bytes)
+                    .expect("Could not decompress compressed_packets");
+                let Ok(chunk) = Trace::parse_from_bytes(&chunk_bytes) else {
+                    panic!("Could not parse compressed_packets");
+                };
+                decompressed_trace.packet.extend(chunk.packet);
+            }
+            _ => decompressed_trace.packet.push(packet),
+        }
+    }
+    decompressed_trace
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{env, fs, process};
+
+    use crate::protos::protos_gen::perfetto_bpftrace::TrackDescriptor;
+    use crate::tests::{call_stack, convert, instant, track_events};
+
+    fn is_compressed(packet: &TracePacket) -> bool {
+        matches!(packet.data, Some(trace_packet::Data::CompressedPackets(_)))
+    }
+
+    #[test]
+    fn round_trip() {
+        let lines = [
+            instant("a", 1_000),
+            call_stack(2_000, 11, "\n f0+1\n main+4\n"),
+            instant("b", 3_000),
+        ];
+        let trace = convert(&lines, &[]);
+
+        let bytes = write_trace(trace.clone(), Some(Compression::Deflate));
+        let compressed = Trace::parse_from_bytes(&bytes).unwrap();
+        assert_eq!(compressed.packet.len(), 1);
+        assert!(is_compressed(&compressed.packet[0]));
+        assert_eq!(decompress_packets(compressed), trace);
+
+        assert_eq!(
+            write_trace(trace.clone(), None),
+            trace.write_to_bytes().unwrap()
+        );
+    }
+
+    #[test]
+    fn chunks_are_about_chunk_size() {
+        let mut trace = Trace::new();
+        for i in 0..10 {
+            let mut track_descriptor = TrackDescriptor::new();
+            track_descriptor.uuid = Some(i);
+            track_descriptor.set_name(i.to_string().repeat(CHUNK_SIZE as usize / 4));
+            let mut packet = TracePacket::new();
+            packet.data = Some(trace_packet::Data::TrackDescriptor(track_descriptor));
+            trace.packet.push(packet);
+        }
+
+        let compressed = compress_packets(trace.clone());
+        // Four packets fill a chunk
+        assert_eq!(compressed.packet.len(), 3);
+        assert!(compressed.packet.iter().all(is_compressed));
+        assert_eq!(decompress_packets(compressed), trace);
+    }
+
+    #[test]
+    fn merge_into_a_compressed_trace() {
+        let existing_file =
+            env::temp_dir().join(format!("btetto-test-{}.zlib.pftrace", process::id()));
+        let existing_file = existing_file.to_str().unwrap();
+        let existing = convert(&[instant("a", 1_000)], &[]);
+        fs::write(
+            existing_file,
+            write_trace(existing, Some(Compression::Deflate)),
+        )
+        .unwrap();
+
+        let merged = convert(&[instant("b", 2_000)], &["--merge-into", existing_file]);
+        fs::remove_file(existing_file).unwrap();
+
+        assert!(!merged.packet.iter().any(is_compressed));
+        let names: Vec<String> = track_events(&merged)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, ["a", "b"]);
+    }
+}
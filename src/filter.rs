@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+use serde_json::Value;
+
+use crate::util;
+
+// --pid, --tid, --comm, --name, --since and --until. A record is dropped when
+// it has a field that doesn't match, records without the field are kept e.g.
+// --name doesn't drop call stack samples. An END goes the way of its BEGIN, so
+// slices are never left half open and a dropped slice doesn't leave an END
+// behind e.g. when --since falls inside it or only the BEGIN has the
+// thread_name.
+pub struct Filter {
+    pids: Vec<u64>,
+    tids: Vec<u64>,
+    comm: Option<Regex>,
+    name: Option<Regex>,
+    // Nanoseconds, after --ts-unit and --ts-offset
+    since: Option<u64>,
+    until: Option<u64>,
+    // Whether each open BEGIN was kept, keyed by track
+    open_slices: HashMap<String, Vec<bool>>,
+}
+
+impl Filter {
+    pub fn new(
+        pids: &[u64],
+        tids: &[u64],
+        comm: Option<&str>,
+        name: Option<&str>,
+        since: Option<u64>,
+        until: Option<u64>,
+    ) -> Filter {
+        Filter {
+            pids: pids.to_vec(),
+            tids: tids.to_vec(),
+            comm: comm.map(compile_regex),
+            name: name.map(compile_regex),
+            since,
+            until,
+            open_slices: HashMap::new(),
+        }
+    }
+
+    // data is a track_event or call_stack record with its ts converted
+    pub fn matches(&mut self, data: &Value) -> bool {
+        let event_type = data
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|pair| pair[0] == "type")
+            .and_then(|pair| pair[1].as_str());
+        if event_type == Some("END") {
+            let begin_matched = self
+                .open_slices
+                .get_mut(&util::slice_track(data))
+                .and_then(|open| open.pop());
+            if let Some(begin_matched) = begin_matched {
+                return begin_matched;
+            }
+        }
+
+        let is_match = self.matches_fields(data);
+        if event_type == Some("BEGIN") {
+            self.open_slices
+                .entry(util::slice_track(data))
+                .or_default()
+                .push(is_match);
+        }
+        is_match
+    }
+
+    fn matches_fields(&self, data: &Value) -> bool {
+        data.as_array()
+            .unwrap()
+            .iter()
+            .skip(1)
+            .all(|pair| match pair[0].as_str() {
+                Some("pid") => self.pids.is_empty() || self.pids.contains(&as_u64(&pair[1])),
+                Some("tid") => self.tids.is_empty() || self.tids.contains(&as_u64(&pair[1])),
+                Some("thread_name") => is_match(&self.comm, &pair[1]),
+                Some("name") => is_match(&self.name, &pair[1]),
+                Some("ts") => {
+                    let ts = as_u64(&pair[1]);
+                    self.since.is_none_or(|since| ts >= since)
+                        && self.until.is_none_or(|until| ts <= until)
+                }
+                _ => true,
+            })
+    }
+}
+
+fn compile_regex(regex: &str) -> Regex {
+    Regex::new(regex).unwrap_or_else(|e| panic!("Invalid regex {regex}: {e}"))
+}
+
+fn as_u64(value: &Value) -> u64 {
+    match value.as_str() {
+        Some(s) => s.parse().unwrap_or(u64::MAX),
+        None => value.as_u64().unwrap_or(u64::MAX),
+    }
+}
+
+fn is_match(regex: &Option<Regex>, value: &Value) -> bool {
+    let Some(regex) = regex else {
+        return true;
+    };
+    match value.as_str() {
+        Some(s) => regex.is_match(s),
+        None => regex.is_match(&value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    fn event(event_type: &str, name: &str, ts: u64, tid: u64) -> Value {
+        json!([
+            "track_event",
+            ["name", name],
+            ["type", event_type],
+            ["ts", ts],
+            ["pid", 1],
+            ["tid", tid],
+            ["thread_name", format!("worker{tid}")]
+        ])
+    }
+
+    #[test]
+    fn pid_and_tid() {
+        let mut filter = Filter::new(&[1], &[2, 3], None, None, None, None);
+        assert!(filter.matches(&event("INSTANT", "a", 1, 2)));
+        assert!(filter.matches(&event("INSTANT", "a", 1, 3)));
+        assert!(!filter.matches(&event("INSTANT", "a", 1, 4)));
+        let mut filter = Filter::new(&[2], &[], None, None, None, None);
+        assert!(!filter.matches(&event("INSTANT", "a", 1, 2)));
+        // Records without the field are kept
+        assert!(filter.matches(&json!(["track_event", ["name", "a"], ["track", "queue"]])));
+    }
+
+    #[test]
+    fn comm_and_name() {
+        let mut filter = Filter::new(&[], &[], Some("^worker[12]$"), None, None, None);
+        assert!(filter.matches(&event("INSTANT", "a", 1, 1)));
+        assert!(!filter.matches(&event("INSTANT", "a", 1, 3)));
+        let mut filter = Filter::new(&[], &[], None, Some("read|write"), None, None);
+        assert!(filter.matches(&event("INSTANT", "vfs_read", 1, 1)));
+        assert!(!filter.matches(&event("INSTANT", "open", 1, 1)));
+        // --name doesn't drop call stack samples
+        assert!(filter.matches(&json!(["call_stack", ["ts", 1], ["pid", 1], ["tid", 1]])));
+    }
+
+    #[test]
+    fn since_and_until() {
+        let mut filter = Filter::new(&[], &[], None, None, Some(10), Some(20));
+        assert!(!filter.matches(&event("INSTANT", "a", 9, 1)));
+        assert!(filter.matches(&event("INSTANT", "a", 10, 1)));
+        assert!(filter.matches(&event("INSTANT", "a", 20, 1)));
+        assert!(!filter.matches(&event("INSTANT", "a", 21, 1)));
+    }
+
+    #[test]
+    fn ends_follow_their_begin() {
+        // The BEGIN is before --since, its END after it
+        let mut filter = Filter::new(&[], &[], None, None, Some(10), Some(20));
+        assert!(!filter.matches(&event("BEGIN", "a", 5, 1)));
+        assert!(filter.matches(&event("BEGIN", "b", 12, 2)));
+        assert!(!filter.matches(&event("END", "a", 15, 1)));
+        // Kept past --until so the slice is closed
+        assert!(filter.matches(&event("END", "b", 25, 2)));
+
+        // Only the BEGIN has the thread_name
+        let mut filter = Filter::new(&[], &[], Some("worker1"), None, None, None);
+        let end = |tid: u64| {
+            json!([
+                "track_event",
+                ["type", "END"],
+                ["ts", 3],
+                ["pid", 1],
+                ["tid", tid]
+            ])
+        };
+        assert!(filter.matches(&event("BEGIN", "outer", 1, 1)));
+        assert!(!filter.matches(&event("BEGIN", "other", 1, 2)));
+        assert!(filter.matches(&event("BEGIN", "inner", 2, 1)));
+        assert!(!filter.matches(&end(2)));
+        assert!(filter.matches(&end(1)));
+        assert!(filter.matches(&end(1)));
+        // An END without a BEGIN is filtered on its own fields
+        assert!(filter.matches(&end(1)));
+    }
+}
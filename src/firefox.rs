@@ -0,0 +1,486 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+use serde_json::{json, Map, Value};
+
+use crate::interned::SequenceState;
+use crate::protos::protos_gen::perfetto_bpftrace::{trace_packet, track_event, Trace};
+use crate::util;
+
+// Version of the Firefox Profiler's processed profile format written here,
+// the first one with the string, frame, func and stack tables in `shared`
+const PROCESSED_PROFILE_VERSION: u64 = 57;
+// Version of the Gecko format the processed profile was made from
+const GECKO_PROFILE_VERSION: u64 = 31;
+
+// Marker phases
+const PHASE_INSTANT: u64 = 0;
+const PHASE_INTERVAL: u64 = 1;
+const PHASE_INTERVAL_START: u64 = 2;
+const PHASE_INTERVAL_END: u64 = 3;
+
+// The tables all threads share. There are no addresses, so a frame is just
+// its function and each function gets one frame.
+#[derive(Default)]
+struct Shared {
+    string_array: Vec<String>,
+    string_ids: HashMap<String, usize>,
+    // Frame index by function name, funcs and frames have the same indexes
+    func_ids: HashMap<String, usize>,
+    func_names: Vec<usize>,
+    stack_ids: HashMap<(Option<usize>, usize), usize>,
+    stack_prefixes: Vec<Option<usize>>,
+    stack_frames: Vec<usize>,
+}
+
+#[derive(Default)]
+struct Thread {
+    name: String,
+    pid: u64,
+    tid: u64,
+    sample_stacks: Vec<Option<usize>>,
+    sample_times: Vec<f64>,
+    marker_names: Vec<usize>,
+    marker_start_times: Vec<Option<f64>>,
+    marker_end_times: Vec<Option<f64>>,
+    marker_phases: Vec<u64>,
+    marker_data: Vec<Map<String, Value>>,
+    // Indexes of the markers for slices that haven't ended yet
+    open_markers: Vec<usize>,
+}
+
+enum Record {
+    Sample {
+        ts: u64,
+        pid: u64,
+        tid: u64,
+        // Root frame first
+        frames: Vec<String>,
+    },
+    Event {
+        ts: u64,
+        track_uuid: u64,
+        type_: track_event::Type,
+        name: String,
+        args: Map<String, Value>,
+    },
+}
+
+// Writes the call stack samples and track events in the trace as a Firefox
+// Profiler processed profile. Thread tracks become threads with their samples
+// and markers; custom tracks become marker-only threads. Returns the number of
+// threads written.
+pub fn write_firefox_profile(trace: &Trace, filename: &str) -> usize {
+    let out = build_profile(trace);
+    let num_threads = out["threads"].as_array().unwrap().len();
+    fs::write(filename, out.to_string()).expect("Could not write Firefox profile file");
+    num_threads
+}
+
+fn build_profile(trace: &Trace) -> Value {
+    let mut sequence_state = SequenceState::default();
+    // Keyed by (pid, tid)
+    let mut threads: BTreeMap<(u64, u64), Thread> = BTreeMap::new();
+    let mut track_threads: HashMap<u64, (u64, u64)> = HashMap::new();
+    // Custom tracks are threads of one pseudo process, with a pseudo tid each
+    let mut next_track_tid = util::TRACK_ID_BASE + 1;
+    let mut records: Vec<Record> = Vec::new();
+
+    for packet in &trace.packet {
+        let interned = sequence_state.update(packet);
+        match &packet.data {
+            Some(trace_packet::Data::TrackDescriptor(track_descriptor)) => {
+                let uuid = track_descriptor.uuid();
+                let (key, name) = match track_descriptor.thread.as_ref() {
+                    Some(thread) => (
+                        (thread.pid() as u64, thread.tid() as u64),
+                        thread.thread_name().to_string(),
+                    ),
+                    None => {
+                        next_track_tid += 1;
+                        (
+                            (util::TRACK_ID_BASE, next_track_tid - 1),
+                            track_descriptor.name().to_string(),
+                        )
+                    }
+                };
+                get_thread(&mut threads, key).name = name;
+                track_threads.insert(uuid, key);
+            }
+            Some(trace_packet::Data::PerfSample(perf_sample)) => {
+                records.push(Record::Sample {
+                    ts: packet.timestamp(),
+                    pid: perf_sample.pid() as u64,
+                    tid: perf_sample.tid() as u64,
+                    frames: interned.get_callstack_names(perf_sample.callstack_iid()),
+                });
+            }
+            Some(trace_packet::Data::TrackEvent(track_event)) => {
+                if track_event.type_() == track_event::Type::TYPE_COUNTER {
+                    continue;
+                }
+                records.push(Record::Event {
+                    ts: packet.timestamp(),
+                    track_uuid: track_event.track_uuid(),
+                    type_: track_event.type_(),
+                    name: interned.get_event_name(track_event),
+                    args: interned.get_event_args(track_event),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    records.sort_by_key(|r| match r {
+        Record::Sample { ts, .. } | Record::Event { ts, .. } => *ts,
+    });
+    let start_ts = match records.first() {
+        Some(Record::Sample { ts, .. }) | Some(Record::Event { ts, .. }) => *ts,
+        None => 0,
+    };
+    let to_ms = |ts: u64| (ts - start_ts) as f64 / 1_000_000.0;
+
+    let mut shared = Shared::default();
+    // Marker fields have to be declared in the schema to show up in the UI
+    let mut marker_fields: BTreeMap<String, &str> = BTreeMap::new();
+
+    for record in records {
+        match record {
+            Record::Sample {
+                ts,
+                pid,
+                tid,
+                frames,
+            } => {
+                let mut stack = None;
+                for frame in frames {
+                    let frame_index = shared.get_frame_index(&frame);
+                    stack = Some(shared.get_stack_index(stack, frame_index));
+                }
+                let thread = get_thread(&mut threads, (pid, tid));
+                thread.sample_stacks.push(stack);
+                thread.sample_times.push(to_ms(ts));
+            }
+            Record::Event {
+                ts,
+                track_uuid,
+                type_,
+                name,
+                mut args,
+            } => {
+                let Some(key) = track_threads.get(&track_uuid) else {
+                    continue;
+                };
+                let thread = threads.get_mut(key).unwrap();
+                for (key, value) in &args {
+                    let format = if value.is_number() {
+                        "integer"
+                    } else {
+                        "string"
+                    };
+                    marker_fields.insert(key.clone(), format);
+                }
+                args.insert("type".to_string(), json!("bpftrace"));
+                let name_index = shared.get_string_index(&name);
+
+                match type_ {
+                    track_event::Type::TYPE_SLICE_BEGIN => {
+                        thread.open_markers.push(thread.marker_names.len());
+                        thread.add_marker(
+                            name_index,
+                            Some(to_ms(ts)),
+                            None,
+                            PHASE_INTERVAL_START,
+                            args,
+                        );
+                    }
+                    track_event::Type::TYPE_SLICE_END => match thread.open_markers.pop() {
+                        Some(index) => {
+                            thread.marker_end_times[index] = Some(to_ms(ts));
+                            thread.marker_phases[index] = PHASE_INTERVAL;
+                            thread.marker_data[index].extend(args);
+                        }
+                        None => thread.add_marker(
+                            name_index,
+                            None,
+                            Some(to_ms(ts)),
+                            PHASE_INTERVAL_END,
+                            args,
+                        ),
+                    },
+                    _ => thread.add_marker(name_index, Some(to_ms(ts)), None, PHASE_INSTANT, args),
+                }
+            }
+        }
+    }
+
+    let process_names: HashMap<u64, String> = threads
+        .values()
+        .filter(|t| t.pid == t.tid)
+        .map(|t| (t.pid, t.name.clone()))
+        .collect();
+
+    // Counter tracks have no markers, leave them out
+    threads.retain(|_, t| t.pid != util::TRACK_ID_BASE || !t.marker_names.is_empty());
+    let threads: Vec<Value> = threads
+        .into_values()
+        .map(|t| {
+            let process_name = if t.pid == util::TRACK_ID_BASE {
+                "bpftrace tracks".to_string()
+            } else {
+                process_names
+                    .get(&t.pid)
+                    .cloned()
+                    .unwrap_or_else(|| format!("pid {}", t.pid))
+            };
+            let num_markers = t.marker_names.len();
+            json!({
+                "name": t.name,
+                "processType": "default",
+                "processName": process_name,
+                "processStartupTime": 0,
+                "processShutdownTime": null,
+                "isMainThread": t.pid == t.tid,
+                "registerTime": 0,
+                "unregisterTime": null,
+                "pausedRanges": [],
+                "pid": t.pid.to_string(),
+                "tid": t.tid,
+                "samples": {
+                    "length": t.sample_stacks.len(),
+                    "stack": t.sample_stacks,
+                    "time": t.sample_times,
+                    "weight": null,
+                    "weightType": "samples",
+                },
+                "markers": {
+                    "length": num_markers,
+                    "name": t.marker_names,
+                    "startTime": t.marker_start_times,
+                    "endTime": t.marker_end_times,
+                    "phase": t.marker_phases,
+                    "category": vec![0; num_markers],
+                    "data": t.marker_data,
+                },
+            })
+        })
+        .collect();
+
+    let marker_fields: Vec<Value> = marker_fields
+        .into_iter()
+        .map(|(key, format)| json!({ "key": key, "label": key, "format": format }))
+        .collect();
+
+    let num_funcs = shared.func_names.len();
+    let num_stacks = shared.stack_frames.len();
+    json!({
+        "meta": {
+            "version": GECKO_PROFILE_VERSION,
+            "preprocessedProfileVersion": PROCESSED_PROFILE_VERSION,
+            "interval": 1,
+            "startTime": 0,
+            "processType": 0,
+            "product": "btetto",
+            "stackwalk": 1,
+            "symbolicated": true,
+            "categories": [
+                { "name": "Other", "color": "grey", "subcategories": ["Other"] },
+            ],
+            "markerSchema": [
+                {
+                    "name": "bpftrace",
+                    "display": ["marker-chart", "marker-table", "timeline-overview"],
+                    "fields": marker_fields,
+                },
+            ],
+        },
+        "libs": [],
+        "shared": {
+            "stringArray": shared.string_array,
+            "stackTable": {
+                "length": num_stacks,
+                "prefix": shared.stack_prefixes,
+                "frame": shared.stack_frames,
+            },
+            "frameTable": {
+                "length": num_funcs,
+                "address": vec![-1; num_funcs],
+                "inlineDepth": vec![0; num_funcs],
+                "category": vec![0; num_funcs],
+                "subcategory": vec![0; num_funcs],
+                "func": (0..num_funcs).collect::<Vec<usize>>(),
+                "nativeSymbol": vec![Value::Null; num_funcs],
+                "innerWindowID": vec![0; num_funcs],
+                "line": vec![Value::Null; num_funcs],
+                "column": vec![Value::Null; num_funcs],
+            },
+            "funcTable": {
+                "length": num_funcs,
+                "name": shared.func_names,
+                "isJS": vec![false; num_funcs],
+                "relevantForJS": vec![false; num_funcs],
+                "resource": vec![-1; num_funcs],
+                "fileName": vec![Value::Null; num_funcs],
+                "lineNumber": vec![Value::Null; num_funcs],
+                "columnNumber": vec![Value::Null; num_funcs],
+            },
+            "resourceTable": {
+                "length": 0,
+                "lib": [],
+                "name": [],
+                "host": [],
+                "type": [],
+            },
+            "nativeSymbols": {
+                "length": 0,
+                "libIndex": [],
+                "address": [],
+                "name": [],
+                "functionSize": [],
+            },
+        },
+        "threads": threads,
+    })
+}
+
+fn get_thread(threads: &mut BTreeMap<(u64, u64), Thread>, key: (u64, u64)) -> &mut Thread {
+    threads.entry(key).or_insert_with(|| Thread {
+        name: "unknown".to_string(),
+        pid: key.0,
+        tid: key.1,
+        ..Default::default()
+    })
+}
+
+impl Shared {
+    fn get_string_index(&mut self, s: &str) -> usize {
+        if let Some(index) = self.string_ids.get(s) {
+            return *index;
+        }
+        let index = self.string_array.len();
+        self.string_array.push(s.to_string());
+        self.string_ids.insert(s.to_string(), index);
+        index
+    }
+
+    fn get_frame_index(&mut self, frame: &str) -> usize {
+        if let Some(index) = self.func_ids.get(frame) {
+            return *index;
+        }
+        let name = self.get_string_index(frame);
+        let index = self.func_names.len();
+        self.func_names.push(name);
+        self.func_ids.insert(frame.to_string(), index);
+        index
+    }
+
+    fn get_stack_index(&mut self, prefix: Option<usize>, frame_index: usize) -> usize {
+        if let Some(index) = self.stack_ids.get(&(prefix, frame_index)) {
+            return *index;
+        }
+        let index = self.stack_frames.len();
+        self.stack_prefixes.push(prefix);
+        self.stack_frames.push(frame_index);
+        self.stack_ids.insert((prefix, frame_index), index);
+        index
+    }
+}
+
+impl Thread {
+    fn add_marker(
+        &mut self,
+        name_index: usize,
+        start_time: Option<f64>,
+        end_time: Option<f64>,
+        phase: u64,
+        data: Map<String, Value>,
+    ) {
+        self.marker_names.push(name_index);
+        self.marker_start_times.push(start_time);
+        self.marker_end_times.push(end_time);
+        self.marker_phases.push(phase);
+        self.marker_data.push(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{env, process};
+
+    use crate::tests::{call_stack, convert};
+
+    #[test]
+    fn firefox_export() {
+        let lines = [
+            call_stack(1_000_000, 10, "\n f0+1\n main+4\n"),
+            call_stack(2_000_000, 10, "\n f1+1\n main+4\n"),
+            json!({"type": "value", "data": ["track_event", ["name", "work"], ["type", "BEGIN"],
+                ["ts", 1_500_000], ["pid", 10], ["tid", 10]]}),
+            json!({"type": "value", "data": ["track_event", ["name", "work"], ["type", "END"],
+                ["ts", 3_000_000], ["pid", 10], ["tid", 10], ["bytes", 42]]}),
+            json!({"type": "value", "data": ["track_event", ["name", "enqueue"], ["type", "INSTANT"],
+                ["ts", 2_000_000], ["track", "queue"]]}),
+        ];
+        let trace = convert(&lines, &[]);
+        let output_file = env::temp_dir().join(format!("btetto-test-{}.ff.json", process::id()));
+        let output_file = output_file.to_str().unwrap();
+        assert_eq!(write_firefox_profile(&trace, output_file), 2);
+        let json: Value = serde_json::from_str(&fs::read_to_string(output_file).unwrap()).unwrap();
+        fs::remove_file(output_file).unwrap();
+
+        assert!(json["meta"]["version"].is_u64());
+        assert!(json["meta"]["preprocessedProfileVersion"].is_u64());
+        let shared = &json["shared"];
+        let strings = shared["stringArray"].as_array().unwrap();
+        let string = |index: &Value| strings[index.as_u64().unwrap() as usize].as_str().unwrap();
+        // Every stack as frame names, leaf first
+        let stack_names = |stack: &Value| {
+            let mut names = Vec::new();
+            let mut stack = stack.clone();
+            while let Some(index) = stack.as_u64() {
+                let frame = &shared["stackTable"]["frame"][index as usize];
+                let func = &shared["frameTable"]["func"][frame.as_u64().unwrap() as usize];
+                names.push(string(
+                    &shared["funcTable"]["name"][func.as_u64().unwrap() as usize],
+                ));
+                stack = shared["stackTable"]["prefix"][index as usize].clone();
+            }
+            names
+        };
+        // main+4 is shared by both stacks
+        assert_eq!(shared["stackTable"]["length"], 3);
+        assert_eq!(shared["funcTable"]["length"], 3);
+
+        let threads = json["threads"].as_array().unwrap();
+        let bash = &threads[0];
+        assert_eq!(bash["name"], "bash");
+        assert_eq!(bash["pid"], "10");
+        assert_eq!(bash["tid"], 10);
+        let samples = &bash["samples"];
+        assert_eq!(samples["length"], 2);
+        assert_eq!(samples["time"], json!([0.0, 1.0]));
+        let stacks: Vec<Vec<&str>> = samples["stack"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(stack_names)
+            .collect();
+        assert_eq!(stacks, [["f0+1", "main+4"], ["f1+1", "main+4"]]);
+        let markers = &bash["markers"];
+        assert_eq!(markers["length"], 1);
+        assert_eq!(string(&markers["name"][0]), "work");
+        assert_eq!(markers["startTime"][0], 0.5);
+        assert_eq!(markers["endTime"][0], 2.0);
+        assert_eq!(markers["phase"][0], 1);
+        assert_eq!(markers["data"][0]["bytes"], 42);
+
+        let queue = &threads[1];
+        assert_eq!(queue["name"], "queue");
+        assert_eq!(queue["processName"], "bpftrace tracks");
+        assert_eq!(queue["samples"]["length"], 0);
+        assert_eq!(string(&queue["markers"]["name"][0]), "enqueue");
+        assert_eq!(queue["markers"]["phase"][0], 0);
+    }
+}
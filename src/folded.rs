@@ -0,0 +1,65 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+use crate::args::FoldedGroupBy;
+use crate::interned::SequenceState;
+use crate::protos::protos_gen::perfetto_bpftrace::{trace_packet, Trace};
+
+// Aggregates the call stack samples in the trace into Brendan Gregg's folded
+// format (one "group;root;...;leaf count" line per unique stack), which
+// flamegraph.pl, inferno and speedscope all read. Returns the number of lines.
+pub fn write_folded(trace: &Trace, group_by: FoldedGroupBy, filename: &str) -> usize {
+    let mut sequence_state = SequenceState::default();
+    let mut thread_names: HashMap<(i32, i32), String> = HashMap::new();
+    let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+
+    for packet in &trace.packet {
+        let interned = sequence_state.update(packet);
+        match &packet.data {
+            Some(trace_packet::Data::TrackDescriptor(track_descriptor)) => {
+                if let Some(thread) = track_descriptor.thread.as_ref() {
+                    thread_names.insert(
+                        (thread.pid(), thread.tid()),
+                        thread.thread_name().to_string(),
+                    );
+                }
+            }
+            Some(trace_packet::Data::PerfSample(perf_sample)) => {
+                if perf_sample.callstack_iid.is_none() {
+                    continue;
+                }
+                let pid = perf_sample.pid() as i32;
+                let tid = perf_sample.tid() as i32;
+                let comm = thread_names
+                    .get(&(pid, tid))
+                    .map_or("unknown", |name| name.as_str());
+
+                let mut frames = Vec::new();
+                match group_by {
+                    FoldedGroupBy::None => {}
+                    FoldedGroupBy::ThreadName => frames.push(comm.to_string()),
+                    FoldedGroupBy::Pid => frames.push(format!("{comm}-{pid}")),
+                    FoldedGroupBy::Tid => frames.push(format!("{comm}-{pid}/{tid}")),
+                }
+                frames.extend(
+                    interned
+                        .get_callstack_names(perf_sample.callstack_iid())
+                        .into_iter()
+                        // ';' separates frames in the folded format
+                        .map(|f| f.replace(';', ":")),
+                );
+
+                *counts.entry(frames.join(";")).or_insert(0) += 1;
+            }
+            _ => {}
+        }
+    }
+
+    let mut out = String::new();
+    for (stack, count) in &counts {
+        out.push_str(&format!("{stack} {count}\n"));
+    }
+    fs::write(filename, out).expect("Could not write folded stacks file");
+
+    counts.len()
+}
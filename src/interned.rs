@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+
+use serde_json::{Map, Value};
+
+use crate::protos::protos_gen::perfetto_bpftrace::{
+    debug_annotation, trace_packet, Frame, Mapping, ProfiledFrameSymbols, TracePacket, TrackEvent,
+};
+
+// Interned data as seen by a reader of the finished trace, used by the
+// exporters that walk the Perfetto packets instead of the bpftrace input
+#[derive(Default)]
+pub struct Interned {
+    pub event_names: HashMap<u64, String>,
+    pub debug_annotation_names: HashMap<u64, String>,
+    pub debug_annotation_string_values: HashMap<u64, String>,
+    pub log_message_bodies: HashMap<u64, String>,
+    pub function_names: HashMap<u64, String>,
+    pub mapping_paths: HashMap<u64, String>,
+    pub source_paths: HashMap<u64, String>,
+    pub mappings: HashMap<u64, Mapping>,
+    pub frames: HashMap<u64, Frame>,
+    // Bottom frame first
+    pub callstacks: HashMap<u64, Vec<u64>>,
+    // Keyed by frame iid
+    pub frame_symbols: HashMap<u64, ProfiledFrameSymbols>,
+}
+
+impl Interned {
+    pub fn get_event_name(&self, track_event: &TrackEvent) -> String {
+        if track_event.has_name() {
+            return track_event.name().to_string();
+        }
+        self.event_names
+            .get(&track_event.name_iid())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // Debug annotations plus the log message, if any, keyed by name
+    pub fn get_event_args(&self, track_event: &TrackEvent) -> Map<String, Value> {
+        let mut args = Map::new();
+        for debug_annotation in &track_event.debug_annotations {
+            let name = if debug_annotation.has_name() {
+                debug_annotation.name().to_string()
+            } else {
+                self.debug_annotation_names
+                    .get(&debug_annotation.name_iid())
+                    .cloned()
+                    .unwrap_or_default()
+            };
+            let value = match &debug_annotation.value {
+                Some(debug_annotation::Value::IntValue(v)) => Value::from(*v),
+                Some(debug_annotation::Value::StringValue(v)) => Value::from(v.clone()),
+                Some(debug_annotation::Value::StringValueIid(iid)) => Value::from(
+                    self.debug_annotation_string_values
+                        .get(iid)
+                        .cloned()
+                        .unwrap_or_default(),
+                ),
+                _ => Value::Null,
+            };
+            args.insert(name, value);
+        }
+
+        if let Some(log_message) = track_event.log_message.as_ref() {
+            let prio = format!("{:?}", log_message.prio());
+            let body = self
+                .log_message_bodies
+                .get(&log_message.body_iid())
+                .cloned()
+                .unwrap_or_default();
+            args.insert(
+                "log".to_string(),
+                Value::from(format!("{}: {}", prio.trim_start_matches("PRIO_"), body)),
+            );
+        }
+
+        args
+    }
+
+    pub fn get_frame_name(&self, frame_iid: u64) -> String {
+        self.frames
+            .get(&frame_iid)
+            .and_then(|f| self.function_names.get(&f.function_name_id()))
+            .cloned()
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+
+    pub fn get_mapping_path(&self, mapping_iid: u64) -> Option<String> {
+        let mapping = self.mappings.get(&mapping_iid)?;
+        if mapping.path_string_ids.is_empty() {
+            return None;
+        }
+        let mut path = String::new();
+        for id in &mapping.path_string_ids {
+            path.push('/');
+            path.push_str(self.mapping_paths.get(id).map_or("", |p| p.as_str()));
+        }
+        Some(path)
+    }
+
+    // Root frame first
+    pub fn get_callstack_names(&self, callstack_iid: u64) -> Vec<String> {
+        self.callstacks
+            .get(&callstack_iid)
+            .map(|frame_ids| frame_ids.iter().map(|f| self.get_frame_name(*f)).collect())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Default)]
+pub struct SequenceState {
+    sequences: HashMap<u32, Interned>,
+}
+
+pub fn is_incremental_state_cleared(packet: &TracePacket) -> bool {
+    packet.sequence_flags() & (trace_packet::SequenceFlags::SEQ_INCREMENTAL_STATE_CLEARED as u32)
+        != 0
+}
+
+impl SequenceState {
+    pub fn get(&self, sequence_id: u32) -> Option<&Interned> {
+        self.sequences.get(&sequence_id)
+    }
+
+    // Must be called for every packet, in order, before reading the interned
+    // data the packet refers to
+    pub fn update(&mut self, packet: &TracePacket) -> &Interned {
+        let sequence_id = packet.trusted_packet_sequence_id();
+        if is_incremental_state_cleared(packet) {
+            self.sequences.remove(&sequence_id);
+        }
+
+        let interned = self.sequences.entry(sequence_id).or_default();
+        if let Some(interned_data) = packet.interned_data.as_ref() {
+            for en in &interned_data.event_names {
+                interned.event_names.insert(en.iid(), en.name().to_string());
+            }
+            for dan in &interned_data.debug_annotation_names {
+                interned
+                    .debug_annotation_names
+                    .insert(dan.iid(), dan.name().to_string());
+            }
+            for is in &interned_data.debug_annotation_string_values {
+                interned
+                    .debug_annotation_string_values
+                    .insert(is.iid(), String::from_utf8_lossy(is.str()).to_string());
+            }
+            for body in &interned_data.log_message_body {
+                interned
+                    .log_message_bodies
+                    .insert(body.iid(), body.body().to_string());
+            }
+            for is in &interned_data.function_names {
+                interned
+                    .function_names
+                    .insert(is.iid(), String::from_utf8_lossy(is.str()).to_string());
+            }
+            for is in &interned_data.mapping_paths {
+                interned
+                    .mapping_paths
+                    .insert(is.iid(), String::from_utf8_lossy(is.str()).to_string());
+            }
+            for is in &interned_data.source_paths {
+                interned
+                    .source_paths
+                    .insert(is.iid(), String::from_utf8_lossy(is.str()).to_string());
+            }
+            for mapping in &interned_data.mappings {
+                interned.mappings.insert(mapping.iid(), mapping.clone());
+            }
+            for frame in &interned_data.frames {
+                interned.frames.insert(frame.iid(), frame.clone());
+            }
+            for callstack in &interned_data.callstacks {
+                interned
+                    .callstacks
+                    .insert(callstack.iid(), callstack.frame_ids.clone());
+            }
+        }
+        if let Some(trace_packet::Data::ProfiledFrameSymbols(frame_symbols)) = &packet.data {
+            interned
+                .frame_symbols
+                .insert(frame_symbols.frame_iid(), frame_symbols.clone());
+        }
+
+        interned
+    }
+}
@@ -0,0 +1,126 @@
+use crate::util;
+
+// Symbols that mark where the kernel text ends. Addresses past them are in
+// data or padding, not in the function before them.
+const END_SYMBOLS: [&str; 2] = ["_etext", "_einittext"];
+
+pub struct Kallsyms {
+    // Sorted by address
+    symbols: Vec<(u64, String)>,
+}
+
+impl Kallsyms {
+    // Reads a /proc/kallsyms formatted file e.g.
+    //   ffffffff81000000 T _stext
+    //   ffffffffc0a01000 t nf_nat_setup_info	[nf_nat]
+    pub fn load(filename: &str) -> Kallsyms {
+        let Ok(lines) = util::read_lines(filename) else {
+            panic!("Could not read kallsyms file {filename}");
+        };
+
+        let kallsyms = Kallsyms::parse(lines.map_while(Result::ok));
+        if kallsyms.symbols.is_empty() {
+            panic!("No symbols found in kallsyms file {filename}. Was it captured as root?");
+        }
+        kallsyms
+    }
+
+    fn parse(lines: impl Iterator<Item = String>) -> Kallsyms {
+        let mut symbols = Vec::new();
+        for line in lines {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 3 {
+                continue;
+            }
+            let Ok(address) = u64::from_str_radix(fields[0], 16) else {
+                continue;
+            };
+            // Unprivileged reads of /proc/kallsyms zero out every address
+            if address == 0 {
+                continue;
+            }
+            // Only code shows up in stacks. Data symbols (bss, data and
+            // read only data) sit between functions and would take their
+            // addresses.
+            if matches!(fields[1], "b" | "B" | "d" | "D" | "r" | "R") {
+                continue;
+            }
+            symbols.push((address, fields[2].to_string()));
+        }
+
+        symbols.sort();
+        Kallsyms { symbols }
+    }
+
+    // Returns "symbol+offset" in the same format bpftrace uses for kstack
+    pub fn resolve(&self, address: u64) -> Option<String> {
+        let index = self.symbols.partition_point(|(a, _)| *a <= address);
+        if index == 0 {
+            return None;
+        }
+        // The last symbol has no known end, so addresses after it are
+        // attributed to it like any other
+        let (symbol_address, name) = &self.symbols[index - 1];
+        if END_SYMBOLS.contains(&name.as_str()) {
+            return None;
+        }
+        Some(format!("{}+{}", name, address - symbol_address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kallsyms() -> Kallsyms {
+        let lines = [
+            "ffffffff81000000 T _stext",
+            "ffffffff81000100 T do_syscall_64",
+            "ffffffff81000180 t __pfx_ksys_read",
+            "ffffffff81000200 r __ksymtab_ksys_read",
+            "ffffffff81000300 D init_task",
+            "ffffffff81000400 T _etext",
+            "ffffffffc0a01000 t nf_nat_setup_info\t[nf_nat]",
+        ];
+        Kallsyms::parse(lines.iter().map(|line| line.to_string()))
+    }
+
+    #[test]
+    fn resolve() {
+        let kallsyms = kallsyms();
+        // Before the first symbol
+        assert_eq!(kallsyms.resolve(0xffffffff80ffffff), None);
+        // Exact match
+        assert_eq!(
+            kallsyms.resolve(0xffffffff81000100),
+            Some("do_syscall_64+0".to_string())
+        );
+        // Inside a function, past the data symbols that were skipped
+        assert_eq!(
+            kallsyms.resolve(0xffffffff81000310),
+            Some("__pfx_ksys_read+400".to_string())
+        );
+        // Past the end of the kernel text
+        assert_eq!(kallsyms.resolve(0xffffffff81000400), None);
+        assert_eq!(kallsyms.resolve(0xffffffff81000500), None);
+        // The last symbol
+        assert_eq!(
+            kallsyms.resolve(0xffffffffc0a01000),
+            Some("nf_nat_setup_info+0".to_string())
+        );
+        assert_eq!(
+            kallsyms.resolve(0xffffffffc0a01040),
+            Some("nf_nat_setup_info+64".to_string())
+        );
+    }
+
+    #[test]
+    fn hidden_addresses_are_skipped() {
+        let lines = [
+            "0000000000000000 T _stext",
+            "0000000000000000 T do_syscall_64",
+        ];
+        let kallsyms = Kallsyms::parse(lines.iter().map(|line| line.to_string()));
+        assert!(kallsyms.symbols.is_empty());
+    }
+}
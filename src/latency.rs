@@ -0,0 +1,204 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+// Number of latest slices of a name the rolling percentiles are taken over
+const ROLLING_WINDOW: usize = 100;
+
+// The exit summary's percentiles come from a histogram with this many buckets
+// per power of 2, so they're within ~2% of the exact values while the memory
+// used stays bounded however long the capture runs
+const BUCKETS_PER_POWER_OF_2: f64 = 16.0;
+
+// --latency-stats: the duration of every BEGIN/END slice, by slice name
+#[derive(Default)]
+pub struct LatencyStats {
+    // (name, ts) of the open slices, keyed by track uuid
+    open_slices: HashMap<u64, Vec<(String, u64)>>,
+    names: BTreeMap<String, NameStats>,
+}
+
+#[derive(Default)]
+struct NameStats {
+    recent: VecDeque<u64>,
+    count: u64,
+    total: u64,
+    min: u64,
+    max: u64,
+    // Number of durations by bucket, see bucket()
+    histogram: BTreeMap<u32, u64>,
+}
+
+// A slice that just ended and the rolling percentiles of its name
+pub struct Latency {
+    pub name: String,
+    pub duration: u64,
+    pub p50: u64,
+    pub p99: u64,
+}
+
+impl LatencyStats {
+    pub fn begin(&mut self, track_uuid: u64, name: &str, ts: u64) {
+        self.open_slices
+            .entry(track_uuid)
+            .or_default()
+            .push((name.to_string(), ts));
+    }
+
+    pub fn end(&mut self, track_uuid: u64, ts: u64) -> Option<Latency> {
+        let (name, begin_ts) = self.open_slices.get_mut(&track_uuid)?.pop()?;
+        let duration = ts.saturating_sub(begin_ts);
+        let stats = self.names.entry(name.clone()).or_default();
+        stats.add(duration);
+
+        let mut window: Vec<u64> = stats.recent.iter().copied().collect();
+        window.sort_unstable();
+        Some(Latency {
+            name,
+            duration,
+            p50: percentile(&window, 50.0),
+            p99: percentile(&window, 99.0),
+        })
+    }
+
+    // Track uuids are reused after a rotation so the slices left open can't
+    // be matched any more
+    pub fn clear_open_slices(&mut self) {
+        self.open_slices.clear();
+    }
+
+    pub fn print_summary(&self) {
+        if self.names.is_empty() {
+            return;
+        }
+        let name_width = self.names.keys().map(|n| n.len()).max().unwrap_or(0).max(4);
+        println!(
+            "{:<name_width$} {:>8} {:>12} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            "name", "count", "total", "min", "max", "p50", "p90", "p99"
+        );
+        for (name, stats) in &self.names {
+            println!(
+                "{:<name_width$} {:>8} {:>12} {:>10} {:>10} {:>10} {:>10} {:>10}",
+                name,
+                stats.count,
+                format_duration(stats.total),
+                format_duration(stats.min),
+                format_duration(stats.max),
+                format_duration(stats.percentile(50.0)),
+                format_duration(stats.percentile(90.0)),
+                format_duration(stats.percentile(99.0)),
+            );
+        }
+    }
+}
+
+impl NameStats {
+    fn add(&mut self, duration: u64) {
+        if self.recent.len() == ROLLING_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(duration);
+        self.min = if self.count == 0 {
+            duration
+        } else {
+            self.min.min(duration)
+        };
+        self.max = self.max.max(duration);
+        self.count += 1;
+        self.total += duration;
+        *self.histogram.entry(bucket(duration)).or_default() += 1;
+    }
+
+    // Nearest rank percentile of all the durations, from the histogram
+    fn percentile(&self, p: f64) -> u64 {
+        let rank = ((p / 100.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in &self.histogram {
+            seen += count;
+            if seen >= rank {
+                return bucket_value(*bucket).clamp(self.min, self.max);
+            }
+        }
+        self.max
+    }
+}
+
+// Log scale bucket of a duration, 0 only holding 0
+fn bucket(duration: u64) -> u32 {
+    if duration == 0 {
+        return 0;
+    }
+    ((duration as f64).log2() * BUCKETS_PER_POWER_OF_2) as u32 + 1
+}
+
+// The middle of a bucket's range
+fn bucket_value(bucket: u32) -> u64 {
+    if bucket == 0 {
+        return 0;
+    }
+    2f64.powf((bucket as f64 - 0.5) / BUCKETS_PER_POWER_OF_2)
+        .round() as u64
+}
+
+// Nearest rank percentile of sorted durations
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn format_duration(ns: u64) -> String {
+    if ns >= 1_000_000_000 {
+        format!("{:.2}s", ns as f64 / 1e9)
+    } else if ns >= 1_000_000 {
+        format!("{:.2}ms", ns as f64 / 1e6)
+    } else if ns >= 1_000 {
+        format!("{:.2}us", ns as f64 / 1e3)
+    } else {
+        format!("{ns}ns")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_are_within_a_few_percent() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket_value(0), 0);
+        for duration in [1, 7, 1_000, 123_456, 5_000_000_000] {
+            let value = bucket_value(bucket(duration)) as f64;
+            assert!((value - duration as f64).abs() <= duration as f64 * 0.025);
+        }
+    }
+
+    #[test]
+    fn summary_percentiles_are_approximate() {
+        let mut stats = NameStats::default();
+        for duration in 1..=1000 {
+            stats.add(duration * 1000);
+        }
+        assert_eq!((stats.count, stats.min, stats.max), (1000, 1000, 1_000_000));
+        assert_eq!(stats.recent.len(), ROLLING_WINDOW);
+        for (p, exact) in [
+            (50.0, 500_000.0),
+            (90.0, 900_000.0),
+            (99.0, 990_000.0),
+            (100.0, 1_000_000.0),
+        ] {
+            let value = stats.percentile(p) as f64;
+            assert!((value - exact).abs() <= exact * 0.025, "p{p} {value}");
+        }
+    }
+
+    #[test]
+    fn slices_are_matched_per_track() {
+        let mut latency_stats = LatencyStats::default();
+        latency_stats.begin(1, "read", 100);
+        latency_stats.begin(2, "write", 150);
+        latency_stats.begin(1, "lock", 200);
+        assert_eq!(latency_stats.end(1, 250).unwrap().name, "lock");
+        let read = latency_stats.end(1, 400).unwrap();
+        assert_eq!((read.name.as_str(), read.duration), ("read", 300));
+        latency_stats.clear_open_slices();
+        assert!(latency_stats.end(2, 500).is_none());
+    }
+}
@@ -0,0 +1,422 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+use serde_json::Value;
+
+use crate::check;
+
+// Keys btetto reads from each record type. Any other key of a track event
+// becomes a debug annotation, so a misspelled key is silently kept as one.
+const TRACK_EVENT_KEYS: [&str; 12] = [
+    "name",
+    "type",
+    "ts",
+    "pid",
+    "tid",
+    "thread_name",
+    "track",
+    "track_parent",
+    "counter_value",
+    "unit",
+    "log",
+    "flow_id",
+];
+const CALL_STACK_KEYS: [&str; 6] = ["ts", "pid", "tid", "thread_name", "ustack", "kstack"];
+
+// A key is reported as a typo of a known key if it is at most one edit per
+// this many characters of the known key away, so uid isn't taken for pid
+const CHARS_PER_TYPO: usize = 5;
+
+// The parts of a print() tuple the lint can reason about. Anything computed
+// at run time, like nsecs or comm, is Other.
+enum Expr {
+    Str(String),
+    Int(i64),
+    Tuple(Vec<Expr>),
+    Other(String),
+}
+
+// `btetto lint-script`: checks the print(( tuples of bpftrace scripts against
+// the same schema as the conversion, without running them. Only literal values
+// can be checked. Returns the number of violations.
+pub fn lint_scripts(filenames: &[String]) -> usize {
+    let print_re = Regex::new(r"\bprint\s*\(\s*\(").unwrap();
+    let mut num_violations = 0;
+    for filename in filenames {
+        let Ok(script) = std::fs::read_to_string(filename) else {
+            panic!("Could not read file {filename}");
+        };
+        let script = strip_comments(&script);
+        let mut num_file_violations = 0;
+        for m in print_re.find_iter(&script) {
+            let open = m.end() - 1;
+            let line = script[..m.start()].matches('\n').count() + 1;
+            let violations = match matching_paren(script.as_bytes(), open) {
+                Some(close) => lint_tuple(&parse_tuple(&script[open + 1..close])),
+                None => vec!["print tuple is never closed".to_string()],
+            };
+            for violation in &violations {
+                println!("{filename}:{line}: {violation}");
+            }
+            num_file_violations += violations.len();
+        }
+        if num_file_violations == 0 {
+            println!("{filename}: ok");
+        }
+        num_violations += num_file_violations;
+    }
+    num_violations
+}
+
+fn lint_tuple(elements: &[Expr]) -> Vec<String> {
+    let record_type = match elements.first() {
+        Some(Expr::Str(record_type)) => record_type.as_str(),
+        _ => return Vec::new(),
+    };
+    match record_type {
+        "track_event" => with_fields(&elements[1..], &TRACK_EVENT_KEYS, check::check_track_event),
+        "call_stack" => with_fields(
+            &elements[1..],
+            &CALL_STACK_KEYS,
+            check::check_call_stack_sample,
+        ),
+        "trigger" => {
+            if elements.len() == 2 && !matches!(elements[1], Expr::Tuple(_)) {
+                Vec::new()
+            } else {
+                vec![
+                    "trigger expects just a name e.g. print((\"trigger\", \"oom_kill\"))"
+                        .to_string(),
+                ]
+            }
+        }
+        // e.g. print(("stdout", "..."))
+        _ => Vec::new(),
+    }
+}
+
+// Checks the (key, value) tuples after the record type, then the record with
+// the same checks as `btetto check`
+fn with_fields(
+    pairs: &[Expr],
+    known_keys: &[&str],
+    check: fn(&HashMap<&str, Value>) -> Vec<String>,
+) -> Vec<String> {
+    let mut violations = Vec::new();
+    let mut event = HashMap::new();
+    for pair in pairs {
+        match pair {
+            Expr::Tuple(pair) if pair.len() == 2 => match &pair[0] {
+                Expr::Str(key) => {
+                    if let Some(known_key) = misspelled_key(key, known_keys) {
+                        violations.push(format!("unknown key {key}, did you mean {known_key}?"));
+                    }
+                    event.insert(key.as_str(), to_value(&pair[1]));
+                }
+                _ => violations.push("keys must be string literals".to_string()),
+            },
+            _ => violations.push(format!(
+                "expecting (key, value) tuples. Found {}",
+                describe(pair)
+            )),
+        }
+    }
+    violations.extend(check(&event));
+    violations
+}
+
+// The value the shared checks see for an expression. Values only known at
+// run time are null.
+fn to_value(expr: &Expr) -> Value {
+    match expr {
+        Expr::Str(s) => Value::from(s.as_str()),
+        Expr::Int(value) => Value::from(*value),
+        Expr::Tuple(elements) => Value::Array(elements.iter().map(to_value).collect()),
+        Expr::Other(_) => Value::Null,
+    }
+}
+
+// The known key a key that isn't one is probably a typo of
+fn misspelled_key<'a>(key: &str, known_keys: &[&'a str]) -> Option<&'a str> {
+    if known_keys.contains(&key) {
+        return None;
+    }
+    known_keys
+        .iter()
+        .map(|known_key| (edit_distance(key, known_key), *known_key))
+        .filter(|(distance, known_key)| *distance <= known_key.len() / CHARS_PER_TYPO)
+        .min()
+        .map(|(_, known_key)| known_key)
+}
+
+// Levenshtein distance
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+// Blanks out // and /* */ comments, keeping the newlines so line numbers
+// still match
+fn strip_comments(script: &str) -> String {
+    let bytes = script.as_bytes();
+    let mut stripped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'"' {
+            let end = string_end(bytes, i);
+            stripped.extend_from_slice(&bytes[i..end]);
+            i = end;
+        } else if bytes[i..].starts_with(b"//") {
+            while i < bytes.len() && bytes[i] != b'\n' {
+                stripped.push(b' ');
+                i += 1;
+            }
+        } else if bytes[i..].starts_with(b"/*") {
+            let end = script[i + 2..]
+                .find("*/")
+                .map_or(bytes.len(), |end| i + 2 + end + 2);
+            stripped.extend(
+                bytes[i..end]
+                    .iter()
+                    .map(|&b| if b == b'\n' { b'\n' } else { b' ' }),
+            );
+            i = end;
+        } else {
+            stripped.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(stripped).unwrap()
+}
+
+// The index just past the string literal starting at start
+fn string_end(bytes: &[u8], start: usize) -> usize {
+    let mut i = start + 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'"' => return i + 1,
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+fn matching_paren(bytes: &[u8], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = open;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' => {
+                i = string_end(bytes, i);
+                continue;
+            }
+            b'(' => depth += 1,
+            b')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+// Splits the inside of a tuple on its top level commas
+fn parse_tuple(inner: &str) -> Vec<Expr> {
+    let bytes = inner.as_bytes();
+    let mut elements = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' => {
+                i = string_end(bytes, i);
+                continue;
+            }
+            b'(' | b'[' | b'{' => depth += 1,
+            b')' | b']' | b'}' => depth -= 1,
+            b',' if depth == 0 => {
+                elements.push(parse_expr(&inner[start..i]));
+                start = i + 1;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    if !inner[start..].trim().is_empty() {
+        elements.push(parse_expr(&inner[start..]));
+    }
+    elements
+}
+
+fn parse_expr(expr: &str) -> Expr {
+    let expr = expr.trim();
+    let bytes = expr.as_bytes();
+    if bytes.first() == Some(&b'"') && string_end(bytes, 0) == bytes.len() {
+        return Expr::Str(expr[1..expr.len() - 1].to_string());
+    }
+    if bytes.first() == Some(&b'(') && matching_paren(bytes, 0) == Some(bytes.len() - 1) {
+        let inner = &expr[1..expr.len() - 1];
+        let mut elements = parse_tuple(inner);
+        // Just parentheses around an expression e.g. (rand % 1000)
+        if elements.len() == 1 && !inner.trim_end().ends_with(',') {
+            return elements.pop().unwrap();
+        }
+        return Expr::Tuple(elements);
+    }
+    match expr.parse::<i64>() {
+        Ok(value) => Expr::Int(value),
+        Err(_) => Expr::Other(expr.to_string()),
+    }
+}
+
+fn describe(expr: &Expr) -> String {
+    match expr {
+        Expr::Str(s) => format!("\"{s}\""),
+        Expr::Int(value) => value.to_string(),
+        Expr::Tuple(elements) => {
+            let elements: Vec<String> = elements.iter().map(describe).collect();
+            format!("({})", elements.join(", "))
+        }
+        Expr::Other(expr) => expr.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lint(tuple: &str) -> Vec<String> {
+        lint_tuple(&parse_tuple(tuple))
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("name", "name"), 0);
+        assert_eq!(edit_distance("nmae", "name"), 2);
+        assert_eq!(edit_distance("nam", "name"), 1);
+        assert_eq!(edit_distance("", "ts"), 2);
+        assert_eq!(edit_distance("track_parnet", "track_parent"), 2);
+    }
+
+    #[test]
+    fn misspelled_keys() {
+        assert_eq!(misspelled_key("name", &TRACK_EVENT_KEYS), None);
+        assert_eq!(
+            misspelled_key("thread_nme", &TRACK_EVENT_KEYS),
+            Some("thread_name")
+        );
+        assert_eq!(
+            misspelled_key("counter_valeu", &TRACK_EVENT_KEYS),
+            Some("counter_value")
+        );
+        // Short keys that differ by a character are other annotations
+        assert_eq!(misspelled_key("uid", &TRACK_EVENT_KEYS), None);
+        assert_eq!(misspelled_key("audit", &TRACK_EVENT_KEYS), None);
+    }
+
+    #[test]
+    fn tuples_are_split_on_top_level_commas() {
+        let elements = parse_tuple(r#""track_event", ("name", "a,b"), ("ts", nsecs)"#);
+        assert_eq!(
+            describe(&Expr::Tuple(elements)),
+            r#"("track_event", ("name", "a,b"), ("ts", nsecs))"#
+        );
+        let elements = parse_tuple(r#""x", ("args", (1, 2)), ("map", @m[1, 2])"#);
+        assert_eq!(elements.len(), 3);
+        match &elements[2] {
+            Expr::Tuple(pair) => assert_eq!(describe(&pair[1]), "@m[1, 2]"),
+            _ => panic!("expecting a tuple"),
+        }
+    }
+
+    #[test]
+    fn parenthesized_expressions_are_not_tuples() {
+        assert!(matches!(parse_expr("(rand % 1000)"), Expr::Other(e) if e == "rand % 1000"));
+        assert!(matches!(parse_expr("(42)"), Expr::Int(42)));
+        assert!(matches!(parse_expr("(42,)"), Expr::Tuple(e) if e.len() == 1));
+        assert!(matches!(parse_expr(r#""a \" b""#), Expr::Str(s) if s == r#"a \" b"#));
+    }
+
+    #[test]
+    fn valid_tuples_have_no_violations() {
+        let slice = r#""track_event", ("name", "a"), ("type", "BEGIN"), ("ts", nsecs),
+            ("pid", pid), ("tid", tid)"#;
+        assert!(lint(slice).is_empty());
+        let sample =
+            r#""call_stack", ("ts", nsecs), ("pid", pid), ("tid", tid), ("ustack", ustack)"#;
+        assert!(lint(sample).is_empty());
+        assert!(lint(r#""trigger", "oom_kill""#).is_empty());
+        assert!(lint(r#""stdout", "hello""#).is_empty());
+    }
+
+    #[test]
+    fn invalid_tuples_are_reported() {
+        let counter = r#""track_event", ("name", "c"), ("type", "COUNTER"), ("ts", nsecs),
+            ("track", "t"), ("unit", "meters")"#;
+        assert_eq!(
+            lint(counter),
+            [
+                "COUNTER without a counter_value",
+                "unknown unit meters, expecting one of unspecified, count, sized_bytes, time_ns",
+            ]
+        );
+        let typo = r#""track_event", ("name", "a"), ("type", "INSTANT"), ("ts", nsecs),
+            ("pid", pid), ("tid", tid), ("thread_nmae", comm)"#;
+        assert_eq!(
+            lint(typo),
+            ["unknown key thread_nmae, did you mean thread_name?"]
+        );
+        let sample = r#""call_stack", ("ts", nsecs), ("pid", pid), ("tid", tid)"#;
+        assert_eq!(
+            lint(sample),
+            ["call stack sample must have a ustack or a kstack or both"]
+        );
+        assert_eq!(lint(r#""trigger", ("name", "x")"#).len(), 1);
+    }
+
+    #[test]
+    fn literals_get_the_same_checks_as_check() {
+        let slice = r#""track_event", ("name", 1), ("type", "BEGN"), ("ts", -1),
+            ("track_parent", "p"), ("log", ("LOUD", $msg))"#;
+        assert_eq!(
+            lint(slice),
+            [
+                "track must have a valid type. Found BEGN",
+                "ts must be a number or a time string. Found -1",
+                "name must be a string",
+                "track_parent without a track",
+                "track event must have either a pid and tid or a track",
+                "unknown log level LOUD, expecting one of UNSPECIFIED, UNUSED, VERBOSE, DEBUG, INFO, WARN, ERROR, FATAL",
+            ]
+        );
+        // Run time values are only checked for being there
+        let counter = r#""track_event", ("name", $name), ("type", $type), ("ts", nsecs),
+            ("track", $track), ("counter_value", @count), ("log", ($level, $msg))"#;
+        assert!(lint(counter).is_empty());
+    }
+
+    #[test]
+    fn comments_are_blanked_out() {
+        let script = "// print((\"x\"))\nprint((\"a // b\")); /* one\ntwo */ x";
+        assert_eq!(
+            strip_comments(script),
+            "               \nprint((\"a // b\"));       \n       x"
+        );
+    }
+}
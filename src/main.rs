@@ -1,78 +1,237 @@
-use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::fs;
+use std::hash::{BuildHasher, Hasher};
 use std::io::{self};
-
-use serde_json::Value;
-
+use std::mem;
+use std::path::Path;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+mod args;
+mod check;
+mod chrome;
+mod clock;
+mod compress;
+mod filter;
+mod firefox;
+mod folded;
+mod interned;
+mod kallsyms;
+mod latency;
+mod lint;
+mod merge;
+mod pprof;
 mod protos;
+mod ring;
+mod sampling;
+mod stats;
+mod symbolize;
+mod text;
 mod util;
 
 use protobuf::Message;
 use protos::protos_gen::perfetto_bpftrace::{
-    counter_descriptor, debug_annotation, profiling, trace_packet, track_descriptor, track_event,
-    Callstack, CounterDescriptor, DebugAnnotation, DebugAnnotationName, EventName, Frame,
-    InternedData, InternedString, LogMessage, LogMessageBody, Mapping, PerfSample,
-    ThreadDescriptor, Trace, TracePacket, TrackDescriptor, TrackEvent,
+    counter_descriptor, debug_annotation, profiling, trace_config, trace_packet, track_descriptor,
+    track_event, Callstack, CounterDescriptor, DataSourceConfig, DebugAnnotation,
+    DebugAnnotationName, EventName, Frame, InternedData, InternedString, LogMessage,
+    LogMessageBody, Mapping, PerfSample, ProfiledFrameSymbols, SystemInfo, ThreadDescriptor, Trace,
+    TraceConfig, TracePacket, TraceUuid, TrackDescriptor, TrackEvent, Trigger, Utsname,
 };
 
 // cargo build && sudo bpftrace ~/jordan.bt -f json | ./target/debug/btetto
 
-struct Ids {
+// Interning state of one packet sequence. Each input source gets its own
+// sequence so interned ids from different sources never mix.
+struct Sequence {
+    id: u32,
+    // The source it's the sequence of, see switch_source()
+    source: String,
+    is_first_packet: bool,
+    // Set after lost events, see add_lost_events()
+    is_packet_dropped: bool,
+    is_first_call_sample: bool,
     call_stack_ids: HashMap<Vec<u64>, u64>,
+    frame_ids: HashMap<String, u64>,
+    mapping_ids: HashMap<String, u64>,
+    string_ids: HashMap<(StringField, String), u64>,
+    interned_data_id: u64,
+}
+
+// The InternedData fields strings are interned in. Each one is its own iid
+// namespace, so a string interned in one still has to be added to another.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum StringField {
+    EventName,
+    DebugAnnotationName,
+    DebugAnnotationStringValue,
+    LogMessageBody,
+    FunctionName,
+    MappingPath,
+    SourcePath,
+}
+
+impl Sequence {
+    fn new(id: u32, source: &str) -> Sequence {
+        Sequence {
+            id,
+            source: source.to_string(),
+            is_first_packet: true,
+            is_packet_dropped: false,
+            is_first_call_sample: true,
+            call_stack_ids: HashMap::new(),
+            frame_ids: HashMap::new(),
+            mapping_ids: HashMap::new(),
+            string_ids: HashMap::new(),
+            interned_data_id: 1,
+        }
+    }
+}
+
+struct Ids {
+    // The sequence of the source being read
+    sequence: Sequence,
+    // The other sources' sequences, keyed by source
+    sequences: HashMap<String, Sequence>,
+    next_sequence_id: u32,
+    // The input being read, by its index in the input files so the same file
+    // given twice is two sources, and whether its records go on a sequence
+    // per cpu
+    input_source: String,
+    sequence_per_cpu: bool,
+    // Top level track the input's custom tracks go under, see --namespace-tracks
+    track_namespace: Option<String>,
+    // Tracks and flows are global so they're shared by all sequences
     flow_name_ids: HashMap<String, u64>,
     name_uuids: HashMap<String, u64>,
     pid_tid_uuids: HashMap<u64, HashMap<u64, u64>>,
-    string_ids: HashMap<String, u64>,
-    interned_data_id: u64,
+    symbolizer: symbolize::Symbolizer,
+    kallsyms: Option<kallsyms::Kallsyms>,
+    frame_symbols: Vec<ProfiledFrameSymbols>,
+    // Map output has no timestamp of its own so it's placed at the latest
+    // event seen before it
+    last_ts: u64,
+    first_ts: Option<u64>,
+    // None leaves the timestamps in Perfetto's default boottime clock
+    clock_id: Option<u32>,
+    ts_unit_ns: u64,
+    ts_offset: i64,
+    // Only set when there are filter options
+    filter: Option<filter::Filter>,
+    // Only set with --sample or --rate-limit
+    sampler: Option<sampling::Sampler>,
+    // Only set with --latency-stats
+    latency_stats: Option<latency::LatencyStats>,
+    // Input records by type, only counted for `btetto stats`
+    record_counts: Option<BTreeMap<String, u64>>,
+    // Only set in --ring-buffer mode
+    ring_buffer: Option<ring::RingBuffer>,
+    // Records without a ts of their own (printf, map, hist...) that came in
+    // before the first event, see parse_unstamped_output(). None once they've
+    // been added.
+    held_records: Option<Vec<Value>>,
+    // --trigger-on event names, and the (name, ts, sequence id) of the
+    // triggers hit whose snapshot hasn't been written yet
+    trigger_names: Vec<String>,
+    pending_triggers: Vec<(String, u64, u32)>,
+    num_triggers: u32,
+    // Serialized size of the packets up to rotation_counted for
+    // --rotate-size, and the number of files rotated out so far
+    rotation_size: u64,
+    rotation_counted: usize,
+    num_rotations: u32,
+    // Taken by the trace being merged into
+    reserved_uuids: HashSet<u64>,
+    reserved_flow_ids: HashSet<u64>,
+    // Off when merging: the existing trace's snapshots are the real ones and
+    // btetto's would be mixed into its clock conversion
+    has_clock_snapshots: bool,
 }
 
-static mut IS_FIRST_PACKET: bool = true;
-static mut IS_FIRST_CALL_SAMPLE: bool = true;
 static mut IS_TRACE_DONE: bool = false;
 static mut TRACK_DESCRIPTOR_UUID: u64 = 1;
 static mut FLOW_UUID: u64 = 1;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
+    let options = args::parse_args(&args);
+    if options.command == args::Command::Check {
+        if options.input_files.is_empty() {
+            panic!("Error: btetto check needs at least one file");
+        }
+        let num_violations = check::check_files(&options.input_files);
+        process::exit(if num_violations > 0 { 1 } else { 0 });
+    }
+    if options.command == args::Command::LintScript {
+        if options.input_files.is_empty() {
+            panic!("Error: btetto lint-script needs at least one script");
+        }
+        let num_violations = lint::lint_scripts(&options.input_files);
+        process::exit(if num_violations > 0 { 1 } else { 0 });
+    }
     let mut trace = Trace::new();
-    let mut ids = Ids {
-        call_stack_ids: HashMap::new(),
-        flow_name_ids: HashMap::new(),
-        name_uuids: HashMap::new(),
-        pid_tid_uuids: HashMap::new(),
-        string_ids: HashMap::new(),
-        interned_data_id: 1,
-    };
-
-    let packet = TracePacket::new();
-    trace.packet.push(packet);
 
-    let args_len = args.len();
-    if args_len > 2 {
-        panic!("btetto only supports one argument, an optional filename.");
-    } else if args_len == 2 {
-        if let Ok(lines) = util::read_lines(args[1].clone()) {
-            for line in lines.flatten() {
-                let parse_json_line = serde_json::from_str(&line);
-                if parse_json_line.is_err() {
+    let existing_trace = options.merge_into.as_deref().map(merge::read_trace);
+    let mut ids = new_ids(&options, existing_trace.as_ref());
+
+    // Read up front so a bad path fails before tracing rather than after
+    let script = options.script.as_deref().map(|script| {
+        fs::read_to_string(script).unwrap_or_else(|_| panic!("Could not read script file {script}"))
+    });
+
+    start_trace(&mut trace, &mut ids, existing_trace.as_ref());
+
+    for (index, input_file) in options.input_files.iter().enumerate() {
+        start_input(&mut trace, &mut ids, &options, index, input_file);
+
+        // bpftrace's text output is accepted as well as `-f json`
+        let mut input_parser = text::InputParser::Undetected;
+        if let Ok(lines) = util::read_lines(input_file) {
+            for line in lines {
+                // A line that isn't UTF-8 is skipped and the rest of the file
+                // still read. Other read errors would repeat on every call.
+                let line = match line {
+                    Ok(line) => line,
+                    Err(e) if e.kind() == io::ErrorKind::InvalidData => continue,
+                    Err(e) => panic!("Could not read file {input_file}: {e}"),
+                };
+                let parse_line = input_parser.parse_line(&line);
+                if parse_line.is_err() {
                     panic!("Error parsing json line {}", &line);
                 }
-                let json_line: Value = parse_json_line.unwrap();
-                let out_type = &json_line["type"];
-                if out_type == "value" {
-                    parse_raw_data(&mut trace, &json_line["data"], &mut ids);
+                for json_line in parse_line.unwrap() {
+                    parse_output(&mut trace, &json_line, &mut ids);
                 }
+                write_trigger_snapshots(
+                    &trace,
+                    &mut ids,
+                    &options,
+                    &args,
+                    script.as_deref(),
+                    false,
+                );
+                rotate_if_due(&mut trace, &mut ids, &options, &args, script.as_deref());
             }
         } else {
-            panic!("Could not read file {}", args[1].clone());
+            panic!("Could not read file {input_file}");
         }
-    } else {
+
+        // Text output can end in the middle of a multi line tuple or histogram
+        for json_line in input_parser.finish() {
+            parse_output(&mut trace, &json_line, &mut ids);
+        }
+    }
+
+    if options.input_files.is_empty() {
         ctrlc::set_handler(|| unsafe {
             IS_TRACE_DONE = true;
         })
         .expect("Error setting Ctrl-C handler");
 
+        let mut input_parser = text::InputParser::Undetected;
         let mut input = String::new();
         loop {
             unsafe {
@@ -87,8 +246,8 @@ fn main() {
             if input.is_empty() {
                 break;
             }
-            let parse_json_line = serde_json::from_str(&input);
-            if parse_json_line.is_err() {
+            let parse_line = input_parser.parse_line(&input);
+            if parse_line.is_err() {
                 unsafe {
                     if !IS_TRACE_DONE {
                         println!("Error parsing json line {}", input.clone());
@@ -96,41 +255,569 @@ fn main() {
                 }
                 break;
             }
-            let json_line: Value = parse_json_line.unwrap();
-            let out_type = &json_line["type"];
-            if out_type == "attached_probes" {
-                let num_probes = &json_line["data"]["probes"];
-                println!("Attaching {} probes...", num_probes);
-            } else if out_type == "value" {
-                parse_raw_data(&mut trace, &json_line["data"], &mut ids);
+            for json_line in parse_line.unwrap() {
+                let out_type = &json_line["type"];
+                if out_type == "attached_probes" {
+                    let num_probes = &json_line["data"]["probes"];
+                    println!("Attaching {} probes...", num_probes);
+                } else {
+                    parse_output(&mut trace, &json_line, &mut ids);
+                }
             }
+            write_trigger_snapshots(&trace, &mut ids, &options, &args, script.as_deref(), false);
+            rotate_if_due(&mut trace, &mut ids, &options, &args, script.as_deref());
             input.clear();
         }
+
+        for json_line in input_parser.finish() {
+            parse_output(&mut trace, &json_line, &mut ids);
+        }
     }
 
-    println!(
-        "Writing {} events to trace file: bpftrace_trace.binpb",
-        trace.packet.len()
+    add_held_records(&mut trace, &mut ids);
+
+    if let Some(sampler) = ids.sampler.as_mut() {
+        let dropped_counts = sampler.finish();
+        if sampler.total_dropped > 0 {
+            println!("Sampling dropped {} events", sampler.total_dropped);
+        }
+        add_dropped_counters(&mut trace, dropped_counts, &mut ids);
+    }
+
+    if let Some(record_counts) = &ids.record_counts {
+        stats::print_stats(&trace, record_counts);
+        if let Some(latency_stats) = &ids.latency_stats {
+            println!("\nLatency:");
+            latency_stats.print_summary();
+        }
+        return;
+    }
+
+    // Triggers near the end of the input get what's left of their window
+    write_trigger_snapshots(&trace, &mut ids, &options, &args, script.as_deref(), true);
+
+    if let Some(ring_buffer) = ids.ring_buffer.as_mut() {
+        if let Some(first_ts) = ring_buffer.window(&mut trace, ids.last_ts) {
+            ids.first_ts = Some(first_ts);
+        }
+    }
+
+    finish_trace(
+        &mut trace,
+        &mut ids,
+        &args,
+        script.as_deref(),
+        existing_trace.as_ref(),
     );
 
-    let out_bytes: Vec<u8> = trace.write_to_bytes().unwrap();
+    if let Some(existing_trace) = existing_trace {
+        let mut merged_trace = existing_trace.trace;
+        merged_trace.packet.append(&mut trace.packet);
+        trace = merged_trace;
+    }
+
+    if let Some(latency_stats) = &ids.latency_stats {
+        latency_stats.print_summary();
+    }
 
-    fs::write("bpftrace_trace.binpb", out_bytes).expect("Could not write Perfetto protobuf file");
+    let output_file = options
+        .output_file
+        .as_deref()
+        .unwrap_or(default_output_file(&options));
+    if options.rotate_every.is_some() || options.rotate_size.is_some() {
+        let output_file = numbered_output_file(output_file, "-", ids.num_rotations + 1);
+        write_output(trace, &options, &output_file);
+    } else {
+        write_output(trace, &options, output_file);
+    }
+}
+
+// The ids and state of a conversion with the given options
+fn new_ids(options: &args::Options, existing_trace: Option<&merge::ExistingTrace>) -> Ids {
+    // Sequence ids continue after the ones in the trace being merged into
+    let first_sequence_id = existing_trace.map_or(1, |e| e.max_sequence_id + 1);
+
+    let mut ids = Ids {
+        sequence: Sequence::new(first_sequence_id, "0"),
+        sequences: HashMap::new(),
+        next_sequence_id: first_sequence_id + 1,
+        input_source: "0".to_string(),
+        sequence_per_cpu: options.sequence_per_cpu,
+        track_namespace: None,
+        flow_name_ids: HashMap::new(),
+        name_uuids: HashMap::new(),
+        pid_tid_uuids: HashMap::new(),
+        symbolizer: symbolize::Symbolizer::new(&options.symbol_dirs),
+        kallsyms: options.kallsyms.as_deref().map(kallsyms::Kallsyms::load),
+        frame_symbols: Vec::new(),
+        last_ts: 0,
+        first_ts: None,
+        clock_id: options.clock.map(clock::get_clock_id),
+        ts_unit_ns: options.ts_unit_ns,
+        ts_offset: options.ts_offset,
+        filter: None,
+        sampler: (options.sample_every.is_some() || options.rate_limit.is_some())
+            .then(|| sampling::Sampler::new(options.sample_every, options.rate_limit)),
+        latency_stats: options.latency_stats.then(latency::LatencyStats::default),
+        record_counts: (options.command == args::Command::Stats).then(BTreeMap::new),
+        ring_buffer: options.ring_buffer.map(ring::RingBuffer::new),
+        held_records: Some(Vec::new()),
+        trigger_names: options.trigger_on.clone(),
+        pending_triggers: Vec::new(),
+        num_triggers: 0,
+        rotation_size: 0,
+        rotation_counted: 0,
+        num_rotations: 0,
+        reserved_uuids: HashSet::new(),
+        has_clock_snapshots: existing_trace.is_none(),
+        reserved_flow_ids: HashSet::new(),
+    };
+
+    let has_filters = !options.pids.is_empty()
+        || !options.tids.is_empty()
+        || options.comm.is_some()
+        || options.name.is_some()
+        || options.since.is_some()
+        || options.until.is_some();
+    if has_filters {
+        let to_ns = |ts: &String| convert_ts_value(&Value::from(ts.as_str()), &ids);
+        ids.filter = Some(filter::Filter::new(
+            &options.pids,
+            &options.tids,
+            options.comm.as_deref(),
+            options.name.as_deref(),
+            options.since.as_ref().map(to_ns),
+            options.until.as_ref().map(to_ns),
+        ));
+    }
+
+    if let Some(existing_trace) = existing_trace {
+        ids.reserved_uuids = existing_trace.track_uuids.clone();
+        ids.reserved_flow_ids = existing_trace.flow_ids.clone();
+        // Thread events go on the existing thread tracks
+        for (pid, tid, uuid) in &existing_trace.thread_tracks {
+            ids.pid_tid_uuids
+                .entry(*pid)
+                .or_default()
+                .insert(*tid, *uuid);
+        }
+    }
+
+    ids
+}
+
+// `btetto stats` counts what's in the input, so the events btetto adds itself
+// (dropped and latency counters, lost_events markers, map counters, histogram
+// summaries and printf logs) are left out. The records are still counted.
+fn is_stats_mode(ids: &Ids) -> bool {
+    ids.record_counts.is_some()
+}
+
+fn start_trace(trace: &mut Trace, ids: &mut Ids, existing_trace: Option<&merge::ExistingTrace>) {
+    let packet = TracePacket::new();
+    trace.packet.push(packet);
+
+    if !existing_trace.is_some_and(|e| e.has_trace_uuid) {
+        add_trace_uuid(trace);
+    }
+    if !existing_trace.is_some_and(|e| e.has_system_info) {
+        add_system_info(trace);
+    }
+    add_clock_snapshot(trace, ids);
+}
+
+fn finish_trace(
+    trace: &mut Trace,
+    ids: &mut Ids,
+    args: &[String],
+    script: Option<&str>,
+    existing_trace: Option<&merge::ExistingTrace>,
+) {
+    ids.track_namespace = None;
+    // A second config would replace the existing trace's in the metadata
+    if !existing_trace.is_some_and(|e| e.has_trace_config) {
+        add_trace_config(trace, args, script);
+    }
+    add_clock_snapshot(trace, ids);
+}
+
+fn update_ts_range(ts: u64, ids: &mut Ids) {
+    ids.last_ts = ids.last_ts.max(ts);
+    ids.first_ts = Some(ids.first_ts.map_or(ts, |first_ts| first_ts.min(ts)));
+}
+
+fn add_trace_uuid(trace: &mut Trace) {
+    // RandomState is seeded from the OS so hashing the time with it gives
+    // an id that's unique across runs and machines
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let mut hashes = [0; 2];
+    for hash in hashes.iter_mut() {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(now);
+        hasher.write_u32(process::id());
+        *hash = hasher.finish() as i64;
+    }
+
+    let mut trace_uuid = TraceUuid::new();
+    trace_uuid.msb = Some(hashes[0]);
+    trace_uuid.lsb = Some(hashes[1]);
+
+    let mut packet = TracePacket::new();
+    packet.data = Some(trace_packet::Data::TraceUuid(trace_uuid));
+    trace.packet.push(packet);
+}
+
+// Snapshots at the start and end of the conversion let the trace processor
+// convert the --clock timestamps to the trace's clock
+fn add_clock_snapshot(trace: &mut Trace, ids: &mut Ids) {
+    if !ids.has_clock_snapshots {
+        return;
+    }
+    let mut packet = TracePacket::new();
+    // The tai clock is scoped to the sequence the events are on
+    packet.optional_trusted_packet_sequence_id = Some(
+        trace_packet::Optional_trusted_packet_sequence_id::TrustedPacketSequenceId(ids.sequence.id),
+    );
+    set_sequence_flags(&mut packet, &mut ids.sequence);
+    packet.data = Some(trace_packet::Data::ClockSnapshot(clock::take_snapshot()));
+    trace.packet.push(packet);
+}
+
+// Describes the machine btetto runs on, which is the traced machine when
+// bpftrace is piped straight into btetto
+fn add_system_info(trace: &mut Trace) {
+    let mut utsname = Utsname::new();
+    utsname.sysname = Some(util::read_sys_value("/proc/sys/kernel/ostype"));
+    utsname.release = Some(util::read_sys_value("/proc/sys/kernel/osrelease"));
+    utsname.version = Some(util::read_sys_value("/proc/sys/kernel/version"));
+    utsname.machine = Some(env::consts::ARCH.to_string());
+
+    let mut system_info = SystemInfo::new();
+    system_info.utsname = Some(utsname).into();
+    system_info.tracing_service_version = Some(format!("btetto {}", env!("CARGO_PKG_VERSION")));
+
+    let mut packet = TracePacket::new();
+    packet.data = Some(trace_packet::Data::SystemInfo(system_info));
+    trace.packet.push(packet);
+}
+
+// How the trace was captured, as the legacy config of a "btetto" data source
+// in a TraceConfig packet so trace processor puts it in the metadata table
+// (trace_config_pbtxt)
+fn add_trace_config(trace: &mut Trace, args: &[String], script: Option<&str>) {
+    let mut legacy_config = format!(
+        "hostname: {}\nkernel_release: {}\nbtetto_version: {}\ncmdline: {}\n",
+        util::read_sys_value("/proc/sys/kernel/hostname"),
+        util::read_sys_value("/proc/sys/kernel/osrelease"),
+        env!("CARGO_PKG_VERSION"),
+        args.join(" ")
+    );
+    if let Some(script) = script {
+        legacy_config.push_str(&format!("script:\n{script}"));
+    }
+
+    let mut data_source_config = DataSourceConfig::new();
+    data_source_config.name = Some("btetto".to_string());
+    data_source_config.legacy_config = Some(legacy_config);
+    let mut data_source = trace_config::DataSource::new();
+    data_source.config = Some(data_source_config).into();
+    let mut trace_config = TraceConfig::new();
+    trace_config.data_sources.push(data_source);
+
+    let mut packet = TracePacket::new();
+    packet.data = Some(trace_packet::Data::TraceConfig(trace_config));
+    trace.packet.push(packet);
+}
+
+fn default_output_file(options: &args::Options) -> &'static str {
+    match options.format {
+        args::OutputFormat::Perfetto => "bpftrace_trace.binpb",
+        args::OutputFormat::Folded => "bpftrace_trace.folded",
+        args::OutputFormat::Pprof => "bpftrace_profile.pb.gz",
+        args::OutputFormat::ChromeJson => "bpftrace_trace.json",
+        args::OutputFormat::Firefox => "bpftrace_profile.json",
+    }
+}
+
+fn write_output(trace: Trace, options: &args::Options, output_file: &str) {
+    match options.format {
+        args::OutputFormat::Perfetto => {
+            println!(
+                "Writing {} events to trace file: {}",
+                trace.packet.len(),
+                output_file
+            );
+
+            let out_bytes: Vec<u8> = compress::write_trace(trace, options.compress);
+
+            fs::write(output_file, out_bytes).expect("Could not write Perfetto protobuf file");
+        }
+        args::OutputFormat::Folded => {
+            let num_stacks = folded::write_folded(&trace, options.folded_group_by, output_file);
+            println!("Wrote {num_stacks} folded stacks to file: {output_file}");
+        }
+        args::OutputFormat::Pprof => {
+            let num_samples = pprof::write_pprof(&trace, output_file);
+            println!("Writing {num_samples} samples to pprof file: {output_file}");
+        }
+        args::OutputFormat::ChromeJson => {
+            let num_events = chrome::write_chrome_json(&trace, output_file);
+            println!("Writing {num_events} events to Chrome JSON trace file: {output_file}");
+        }
+        args::OutputFormat::Firefox => {
+            let num_threads = firefox::write_firefox_profile(&trace, output_file);
+            println!("Writing {num_threads} threads to Firefox profile file: {output_file}");
+        }
+    }
+}
+
+// Writes a snapshot for each trigger whose window after the trigger has
+// passed, or for all of them once the input is done
+fn write_trigger_snapshots(
+    trace: &Trace,
+    ids: &mut Ids,
+    options: &args::Options,
+    args: &[String],
+    script: Option<&str>,
+    is_done: bool,
+) {
+    // `btetto stats` doesn't write any files
+    if options.command == args::Command::Stats {
+        return;
+    }
+    while let Some((name, ts, sequence_id)) = ids.pending_triggers.first().cloned() {
+        if !is_done && ids.last_ts < ts + options.trigger_after {
+            break;
+        }
+        ids.pending_triggers.remove(0);
+        ids.num_triggers += 1;
+
+        let start_ts = ts.saturating_sub(options.trigger_before);
+        let end_ts = ts + options.trigger_after;
+        let mut snapshot = match &ids.ring_buffer {
+            Some(ring_buffer) => ring_buffer.snapshot(trace, start_ts, end_ts),
+            None => ring::snapshot(trace, start_ts, end_ts),
+        };
+
+        // Each snapshot is a trace of its own, with how it was captured
+        snapshot.packet.retain(|p| {
+            !matches!(
+                p.data,
+                Some(trace_packet::Data::TraceUuid(_)) | Some(trace_packet::Data::TraceConfig(_))
+            )
+        });
+        add_trace_uuid(&mut snapshot);
+        add_trace_config(&mut snapshot, args, script);
+
+        let mut trigger = Trigger::new();
+        trigger.trigger_name = Some(name.clone());
+        trigger.producer_name = Some("btetto".to_string());
+        // On the sequence the trigger came in on, where a sequence scoped
+        // --clock like tai has its clock snapshot
+        let mut packet = TracePacket::new();
+        packet.optional_trusted_packet_sequence_id = Some(
+            trace_packet::Optional_trusted_packet_sequence_id::TrustedPacketSequenceId(sequence_id),
+        );
+        packet.timestamp = Some(ts);
+        packet.timestamp_clock_id = ids.clock_id;
+        packet.data = Some(trace_packet::Data::Trigger(trigger));
+        snapshot.packet.push(packet);
+
+        println!("Trigger {name} hit");
+        let output_file = options
+            .output_file
+            .as_deref()
+            .unwrap_or(default_output_file(options));
+        write_output(
+            snapshot,
+            options,
+            &numbered_output_file(output_file, "_trigger_", ids.num_triggers),
+        );
+    }
+}
+
+// e.g. bpftrace_trace.binpb -> bpftrace_trace_trigger_0001.binpb
+fn numbered_output_file(output_file: &str, separator: &str, num: u32) -> String {
+    let name_start = output_file.rfind('/').map_or(0, |i| i + 1);
+    let ext_start = output_file[name_start..]
+        .find('.')
+        .map_or(output_file.len(), |i| name_start + i);
+    format!(
+        "{}{}{:04}{}",
+        &output_file[..ext_start],
+        separator,
+        num,
+        &output_file[ext_start..]
+    )
+}
+
+// Writes the trace so far to the next --rotate-every/--rotate-size file and
+// starts a new self contained one: new track descriptors, sequences starting
+// from a cleared incremental state and all strings interned again
+fn rotate_if_due(
+    trace: &mut Trace,
+    ids: &mut Ids,
+    options: &args::Options,
+    args: &[String],
+    script: Option<&str>,
+) {
+    if options.command == args::Command::Stats {
+        return;
+    }
+    // A trigger waiting for its --trigger-after window needs the events
+    // before it, so the rotation waits for its snapshot
+    if !ids.pending_triggers.is_empty() {
+        return;
+    }
+    let is_span_due = options
+        .rotate_every
+        .is_some_and(|every| ids.first_ts.is_some_and(|ts| ids.last_ts >= ts + every));
+    let is_size_due = options.rotate_size.is_some_and(|size| {
+        for packet in &trace.packet[ids.rotation_counted..] {
+            ids.rotation_size += packet.compute_size();
+        }
+        ids.rotation_counted = trace.packet.len();
+        ids.rotation_size >= size
+    });
+    if !is_span_due && !is_size_due {
+        return;
+    }
+
+    let track_namespace = ids.track_namespace.clone();
+    finish_trace(trace, ids, args, script, None);
+    ids.num_rotations += 1;
+    let output_file = options
+        .output_file
+        .as_deref()
+        .unwrap_or(default_output_file(options));
+    write_output(
+        mem::take(trace),
+        options,
+        &numbered_output_file(output_file, "-", ids.num_rotations),
+    );
+
+    // Other sources get a new sequence, and clock snapshot, when next seen
+    ids.sequence = Sequence::new(ids.sequence.id, &ids.sequence.source);
+    ids.sequences.clear();
+    ids.flow_name_ids.clear();
+    ids.name_uuids.clear();
+    ids.pid_tid_uuids.clear();
+    if let Some(latency_stats) = ids.latency_stats.as_mut() {
+        latency_stats.clear_open_slices();
+    }
+    ids.first_ts = None;
+    ids.rotation_size = 0;
+    ids.rotation_counted = 0;
+    ids.track_namespace = track_namespace;
+    unsafe {
+        TRACK_DESCRIPTOR_UUID = 1;
+        FLOW_UUID = 1;
+    }
+    start_trace(trace, ids, None);
+}
+
+fn parse_output(trace: &mut Trace, json_line: &Value, ids: &mut Ids) {
+    if let Some(record_counts) = ids.record_counts.as_mut() {
+        let record_type = match json_line["type"].as_str() {
+            Some("value") => json_line["data"][0].as_str().unwrap_or("value"),
+            Some(record_type) => record_type,
+            None => "unknown",
+        };
+        *record_counts.entry(record_type.to_string()).or_default() += 1;
+    }
+
+    match (json_line["type"].as_str(), ids.held_records.as_mut()) {
+        (Some("value"), _) => parse_raw_data(trace, &json_line["data"], ids),
+        (_, Some(held_records)) => held_records.push(json_line.clone()),
+        _ => parse_unstamped_output(trace, json_line, ids),
+    }
+    if ids.first_ts.is_some() {
+        add_held_records(trace, ids);
+    }
+
+    if let Some(ring_buffer) = ids.ring_buffer.as_mut() {
+        ring_buffer.trim(trace, ids.last_ts);
+    }
+}
+
+// Records that are stamped with the ts of the latest event. Before the first
+// event there's no ts to give them, so they're held until there is one.
+fn parse_unstamped_output(trace: &mut Trace, json_line: &Value, ids: &mut Ids) {
+    match json_line["type"].as_str() {
+        Some("map") => add_map_counters(trace, &json_line["data"], false, ids),
+        Some("stats") => add_map_counters(trace, &json_line["data"], true, ids),
+        Some("hist") | Some("lhist") => add_histogram_summaries(trace, &json_line["data"], ids),
+        Some("lost_events") => add_lost_events(trace, &json_line["data"], ids),
+        Some("printf") | Some("time") => add_bpftrace_log(trace, json_line, ids),
+        _ => {}
+    }
+}
+
+// Adds the records held back by parse_output() with the first event's ts, or
+// at 0 when the input ends without any events
+fn add_held_records(trace: &mut Trace, ids: &mut Ids) {
+    for json_line in ids.held_records.take().unwrap_or_default() {
+        parse_unstamped_output(trace, &json_line, ids);
+    }
 }
 
 fn parse_raw_data(trace: &mut Trace, data: &Value, ids: &mut Ids) {
     assert!(data.is_array(), "Expecting data to be a array.");
 
-    if data.as_array().unwrap().len() == 0 {
+    if data.as_array().unwrap().is_empty() {
         return;
     }
 
+    if ids.sequence_per_cpu {
+        let cpu = data
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|pair| pair[0] == "cpu")
+            .map(|pair| pair[1].to_string());
+        let source = match cpu {
+            Some(cpu) => format!("{} cpu {}", ids.input_source, cpu),
+            None => ids.input_source.clone(),
+        };
+        switch_source(trace, &source, ids);
+    }
+
     let data_type = &data[0];
 
     if data_type == "track_event" {
+        let data = convert_ts(data, ids);
+        if is_filtered(&data, ids) || is_sampled_out(trace, &data, ids) {
+            return;
+        }
+        let name = data
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|pair| pair[0] == "name")
+            .and_then(|pair| pair[1].as_str());
+        if let Some(name) = name.filter(|name| ids.trigger_names.iter().any(|n| n == name)) {
+            let ts = data.as_array().unwrap().iter().find(|pair| pair[0] == "ts");
+            let ts = ts.and_then(|pair| pair[1].as_u64()).unwrap_or(ids.last_ts);
+            ids.pending_triggers
+                .push((name.to_string(), ts, ids.sequence.id));
+        }
         add_track_event(trace, &data, ids);
     } else if data_type == "call_stack" {
+        let data = convert_ts(data, ids);
+        if is_filtered(&data, ids) || is_sampled_out(trace, &data, ids) {
+            return;
+        }
         add_call_stack_sample(trace, &data, ids);
+    } else if data_type == "trigger" {
+        // e.g. print(("trigger", "oom_kill"))
+        let Some(name) = data[1].as_str() else {
+            panic!("Expecting a trigger name. Found {data}");
+        };
+        ids.pending_triggers
+            .push((name.to_string(), ids.last_ts, ids.sequence.id));
     } else if data_type == "stdout" {
         println!("{}", data[1].as_str().unwrap());
     } else {
@@ -138,27 +825,95 @@ fn parse_raw_data(trace: &mut Trace, data: &Value, ids: &mut Ids) {
     }
 }
 
+// --pid, --tid, --comm, --name, --since and --until
+fn is_filtered(data: &Value, ids: &mut Ids) -> bool {
+    ids.filter
+        .as_mut()
+        .is_some_and(|filter| !filter.matches(data))
+}
+
+// --sample and --rate-limit
+fn is_sampled_out(trace: &mut Trace, data: &Value, ids: &mut Ids) -> bool {
+    let Some(sampler) = ids.sampler.as_mut() else {
+        return false;
+    };
+    let (is_kept, dropped_counts) = sampler.sample(data);
+    add_dropped_counters(trace, dropped_counts, ids);
+    !is_kept
+}
+
+// A counter track per event name of the events dropped each second
+fn add_dropped_counters(
+    trace: &mut Trace,
+    dropped_counts: Vec<sampling::DroppedCount>,
+    ids: &mut Ids,
+) {
+    if is_stats_mode(ids) {
+        return;
+    }
+    for (name, ts, num_dropped) in dropped_counts {
+        let event = json!([
+            "track_event",
+            ["name", "dropped"],
+            ["type", "COUNTER"],
+            ["ts", ts],
+            ["track", format!("{name} dropped")],
+            ["counter_value", num_dropped]
+        ]);
+        add_track_event(trace, &event, ids);
+    }
+}
+
+// Converts the record's ts to nanoseconds, applying --ts-unit and --ts-offset.
+// Done here rather than in add_track_event so the events btetto generates
+// itself, which are already in nanoseconds, aren't converted twice.
+fn convert_ts(data: &Value, ids: &Ids) -> Value {
+    let mut data = data.clone();
+    for pair in data.as_array_mut().unwrap().iter_mut().skip(1) {
+        if pair[0] == "ts" {
+            pair[1] = Value::from(convert_ts_value(&pair[1], ids));
+        }
+    }
+    data
+}
+
+fn convert_ts_value(ts: &Value, ids: &Ids) -> u64 {
+    let ts_ns = if let Some(ts) = ts.as_i64() {
+        ts * ids.ts_unit_ns as i64
+    } else if let Some(ts) = ts.as_f64() {
+        (ts * ids.ts_unit_ns as f64) as i64
+    } else if let Some(ts_str) = ts.as_str() {
+        match ts_str.parse::<i64>() {
+            Ok(ts) => ts * ids.ts_unit_ns as i64,
+            Err(_) => util::parse_time_str(ts_str)
+                .unwrap_or_else(|| panic!("Error: Could not parse ts {ts_str}")),
+        }
+    } else {
+        panic!("Error: ts must be a number or a time string. Found {ts}");
+    };
+    let ts_ns = ts_ns + ids.ts_offset;
+    assert!(ts_ns >= 0, "Error: ts {ts} is negative after --ts-offset");
+    ts_ns as u64
+}
+
 fn add_track_descriptor_name(
     track_name: &str,
     parent_name: Option<&str>,
     trace: &mut Trace,
     ids: &mut Ids,
 ) -> u64 {
-    let full_name;
-    if parent_name.is_some() {
-        let parent = parent_name.unwrap();
-        full_name = format!("{parent}/{track_name}");
+    let full_name = if let Some(parent) = parent_name {
+        format!("{parent}/{track_name}")
     } else {
-        full_name = track_name.to_string();
-    }
+        track_name.to_string()
+    };
 
-    let maybe_uuid = get_uuid_for_name(&full_name, &ids);
-    if maybe_uuid.is_some() {
-        return maybe_uuid.unwrap().clone();
+    if let Some(uuid) = get_uuid_for_name(&full_name, ids) {
+        return uuid;
     }
 
-    let uuid = gen_uuid();
-    ids.name_uuids.insert(full_name.to_string(), uuid.clone());
+    let uuid = gen_uuid(ids);
+    ids.name_uuids.insert(full_name.to_string(), uuid);
 
     let mut packet = TracePacket::new();
     let mut track_descriptor = TrackDescriptor::new();
@@ -167,11 +922,11 @@ fn add_track_descriptor_name(
     ));
     track_descriptor.uuid = Some(uuid);
 
-    if parent_name.is_some() {
-        let mut parent_uuid = get_uuid_for_name(&parent_name.unwrap(), &ids);
+    if let Some(parent) = parent_name {
+        let mut parent_uuid = get_uuid_for_name(parent, ids);
         if parent_uuid.is_none() {
             parent_uuid = Some(add_track_descriptor_name(
-                parent_name.unwrap(),
+                parent,
                 None,
                 trace,
                 ids,
@@ -183,24 +938,29 @@ fn add_track_descriptor_name(
     packet.data = Some(trace_packet::Data::TrackDescriptor(track_descriptor));
     trace.packet.push(packet);
 
-    return uuid;
+    uuid
 }
 
 fn add_track_descriptor_counter(
     counter_name: &str,
     unit: Option<&str>,
+    parent_name: Option<&str>,
     trace: &mut Trace,
     ids: &mut Ids,
 ) -> u64 {
-    let maybe_uuid = get_uuid_for_name(&counter_name, &ids);
-    if maybe_uuid.is_some() {
+    let full_name = if let Some(parent) = parent_name {
+        format!("{parent}/{counter_name}")
+    } else {
+        counter_name.to_string()
+    };
+
+    if let Some(uuid) = get_uuid_for_name(&full_name, ids) {
         // Already have this track descriptor, no need to re-add it
-        return maybe_uuid.unwrap();
+        return uuid;
     }
 
-    let uuid = gen_uuid();
-    ids.name_uuids
-        .insert(counter_name.to_string(), uuid.clone());
+    let uuid = gen_uuid(ids);
+    ids.name_uuids.insert(full_name, uuid);
 
     let mut packet = TracePacket::new();
     let mut track_descriptor = TrackDescriptor::new();
@@ -209,11 +969,24 @@ fn add_track_descriptor_counter(
     ));
     track_descriptor.uuid = Some(uuid);
 
+    if let Some(parent) = parent_name {
+        let mut parent_uuid = get_uuid_for_name(parent, ids);
+        if parent_uuid.is_none() {
+            parent_uuid = Some(add_track_descriptor_name(
+                parent,
+                None,
+                trace,
+                ids,
+            ));
+        }
+        track_descriptor.parent_uuid = parent_uuid;
+    }
+
     let mut counter_descriptor = CounterDescriptor::new();
 
     // Count is the default
-    if unit.is_some() {
-        match unit.unwrap() {
+    if let Some(unit) = unit {
+        match unit {
             "unspecified" => {
                 counter_descriptor.unit = Some(counter_descriptor::Unit::UNIT_UNSPECIFIED.into())
             }
@@ -224,7 +997,7 @@ fn add_track_descriptor_counter(
             "time_ns" => {
                 counter_descriptor.unit = Some(counter_descriptor::Unit::UNIT_TIME_NS.into())
             }
-            _ => panic!("Error: Unknown unit type {}", unit.unwrap()),
+            _ => panic!("Error: Unknown unit type {unit}"),
         }
     } else {
         counter_descriptor.unit = Some(counter_descriptor::Unit::UNIT_COUNT.into());
@@ -234,7 +1007,7 @@ fn add_track_descriptor_counter(
     packet.data = Some(trace_packet::Data::TrackDescriptor(track_descriptor));
     trace.packet.push(packet);
 
-    return uuid;
+    uuid
 }
 
 // Example track events
@@ -265,19 +1038,38 @@ fn add_track_event(trace: &mut Trace, data: &Value, ids: &mut Ids) {
     if event.contains_key("track") {
         let track_name = if event["track"].is_number() { event["track"].as_u64().unwrap().to_string() } else { event["track"].as_str().unwrap().to_string() };
 
+        // With --namespace-tracks the input's tracks go under a track named
+        // after the input file
+        let namespace = ids.track_namespace.clone();
+
         if event_type == "COUNTER" {
             if event.contains_key("unit") {
                 track_uuid = Some(add_track_descriptor_counter(
                     &track_name,
                     event["unit"].as_str(),
+                    namespace.as_deref(),
                     trace,
                     ids,
                 ));
             } else {
-                track_uuid = Some(add_track_descriptor_counter(&track_name, None, trace, ids));
+                track_uuid = Some(add_track_descriptor_counter(
+                    &track_name,
+                    None,
+                    namespace.as_deref(),
+                    trace,
+                    ids,
+                ));
             }
         } else if event.contains_key("track_parent") {
-            let track_parent = if event["track_parent"].is_number() { event["track_parent"].as_u64().unwrap().to_string() } else { event["track_parent"].as_str().unwrap().to_string() };
+            let mut track_parent = if event["track_parent"].is_number() {
+                event["track_parent"].as_u64().unwrap().to_string()
+            } else {
+                event["track_parent"].as_str().unwrap().to_string()
+            };
+            if let Some(namespace) = &namespace {
+                add_track_descriptor_name(&track_parent, Some(namespace), trace, ids);
+                track_parent = format!("{namespace}/{track_parent}");
+            }
             track_uuid = Some(add_track_descriptor_name(
                 &track_name,
                 Some(&track_parent),
@@ -285,12 +1077,17 @@ fn add_track_event(trace: &mut Trace, data: &Value, ids: &mut Ids) {
                 ids,
             ));
         } else {
-            track_uuid = Some(add_track_descriptor_name(&track_name, None, trace, ids));
+            track_uuid = Some(add_track_descriptor_name(
+                &track_name,
+                namespace.as_deref(),
+                trace,
+                ids,
+            ));
         }
     } else if event.contains_key("pid") && event.contains_key("tid") {
         let pid = event["pid"].as_u64().unwrap();
         let tid = event["tid"].as_u64().unwrap();
-        track_uuid = get_uuid_for_pid_tid(&pid, &tid, &ids);
+        track_uuid = get_uuid_for_pid_tid(&pid, &tid, ids);
         if track_uuid.is_none() {
             let thread_name = if event.contains_key("thread_name") {
                 event["thread_name"].as_str()
@@ -311,17 +1108,18 @@ fn add_track_event(trace: &mut Trace, data: &Value, ids: &mut Ids) {
     }
 
     let mut packet = TracePacket::new();
-    packet.optional_trusted_packet_sequence_id =
-        Some(trace_packet::Optional_trusted_packet_sequence_id::TrustedPacketSequenceId(1));
+    packet.optional_trusted_packet_sequence_id = Some(
+        trace_packet::Optional_trusted_packet_sequence_id::TrustedPacketSequenceId(ids.sequence.id),
+    );
 
     let mut track_event = TrackEvent::new();
     track_event.track_uuid = track_uuid;
 
-    set_sequence_flags(&mut packet);
+    set_sequence_flags(&mut packet, &mut ids.sequence);
 
     let mut interned_data = InternedData::new();
     let event_name = event["name"].as_str().unwrap();
-    let string_id_pair = get_string_id(event_name, ids);
+    let string_id_pair = get_string_id(event_name, StringField::EventName, ids);
 
     if string_id_pair.1 {
         let mut en = EventName::new();
@@ -333,6 +1131,8 @@ fn add_track_event(trace: &mut Trace, data: &Value, ids: &mut Ids) {
     track_event.name_field = Some(track_event::Name_field::NameIid(string_id_pair.0));
 
     packet.timestamp = Some(event["ts"].as_u64().unwrap());
+    packet.timestamp_clock_id = ids.clock_id;
+    update_ts_range(event["ts"].as_u64().unwrap(), ids);
 
     track_event.type_ = Some(util::get_track_event_type(event_type).into());
 
@@ -348,47 +1148,53 @@ fn add_track_event(trace: &mut Trace, data: &Value, ids: &mut Ids) {
             "Error: log tuple value must be another tuple e.g. ('log', ('WARN', 'my log message'))"
         );
         let log_val = event["log"].as_array().unwrap();
-        let string_id_pair = get_string_id(log_val[1].as_str().unwrap(), ids);
-        let body_iid;
+        let string_id_pair = get_string_id(
+            log_val[1].as_str().unwrap(),
+            StringField::LogMessageBody,
+            ids,
+        );
         if string_id_pair.1 {
-            ids.interned_data_id += 1;
             let mut log_message_body = LogMessageBody::new();
-            log_message_body.iid = Some(ids.interned_data_id);
+            log_message_body.iid = Some(string_id_pair.0);
             log_message_body.body = Some(log_val[1].as_str().unwrap().to_string());
             interned_data.log_message_body.push(log_message_body);
-            body_iid = ids.interned_data_id;
-        } else {
-            body_iid = string_id_pair.0 + 1;
         }
         let mut log_message = LogMessage::new();
-        log_message.body_iid = Some(body_iid);
+        log_message.body_iid = Some(string_id_pair.0);
 
         let log_level = log_val[0].as_str().unwrap();
         log_message.prio = Some(util::get_log_level(log_level).into());
         track_event.log_message = Some(log_message).into();
     }
 
+    // The annotations below consume the event
+    let slice = (
+        event_type.to_string(),
+        event_name.to_string(),
+        event["ts"].as_u64().unwrap(),
+    );
+
     if event_type != "COUNTER" {
         for (key, value) in event.into_iter() {
             if util::is_event_field(key) {
                 continue;
             }
             if key == "flow_id" {
-                let flow_id;
-                if value.is_number() {
-                    flow_id = value.as_u64().unwrap().to_string();
+                let flow_id = if value.is_number() {
+                    value.as_u64().unwrap().to_string()
                 } else {
-                    flow_id = value.as_str().unwrap().to_string();
-                }
+                    value.as_str().unwrap().to_string()
+                };
                 if !ids.flow_name_ids.contains_key(&flow_id) {
-                    ids.flow_name_ids.insert(flow_id.clone(), gen_flow_id());
+                    let new_flow_id = gen_flow_id(ids);
+                    ids.flow_name_ids.insert(flow_id.clone(), new_flow_id);
                 }
                 let flow_id = ids.flow_name_ids[&flow_id];
                 track_event.flow_ids.push(flow_id);
                 continue;
             }
             let mut debug_annotation = DebugAnnotation::new();
-            let string_id_pair = get_string_id(key, ids);
+            let string_id_pair = get_string_id(key, StringField::DebugAnnotationName, ids);
             debug_annotation.name_field =
                 Some(debug_annotation::Name_field::NameIid(string_id_pair.0));
 
@@ -400,7 +1206,11 @@ fn add_track_event(trace: &mut Trace, data: &Value, ids: &mut Ids) {
             }
 
             if value.is_string() {
-                let string_value_id_pair = get_string_id(value.as_str().unwrap(), ids);
+                let string_value_id_pair = get_string_id(
+                    value.as_str().unwrap(),
+                    StringField::DebugAnnotationStringValue,
+                    ids,
+                );
                 debug_annotation.value = Some(debug_annotation::Value::StringValueIid(
                     string_value_id_pair.0,
                 ));
@@ -423,6 +1233,210 @@ fn add_track_event(trace: &mut Trace, data: &Value, ids: &mut Ids) {
     packet.interned_data = Some(interned_data).into();
     packet.data = Some(trace_packet::Data::TrackEvent(track_event));
     trace.packet.push(packet);
+
+    if let (Some(latency_stats), Some(track_uuid)) = (ids.latency_stats.as_mut(), track_uuid) {
+        let (event_type, event_name, ts) = slice;
+        match event_type.as_str() {
+            "BEGIN" => latency_stats.begin(track_uuid, &event_name, ts),
+            "END" => {
+                if let Some(latency) = latency_stats.end(track_uuid, ts) {
+                    add_latency_counters(trace, latency, ts, ids);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// --latency-stats counter tracks of each slice's duration and the rolling
+// p50/p99 of the slices with the same name
+fn add_latency_counters(trace: &mut Trace, latency: latency::Latency, ts: u64, ids: &mut Ids) {
+    if is_stats_mode(ids) {
+        return;
+    }
+    let counters = [
+        ("latency", latency.duration),
+        ("latency p50", latency.p50),
+        ("latency p99", latency.p99),
+    ];
+    for (counter, value) in counters {
+        let event = json!([
+            "track_event",
+            ["name", counter],
+            ["type", "COUNTER"],
+            ["ts", ts],
+            ["track", format!("{} {counter}", latency.name)],
+            ["counter_value", value],
+            ["unit", "time_ns"]
+        ]);
+        add_track_event(trace, &event, ids);
+    }
+}
+
+// bpftrace couldn't keep up and dropped events e.g.
+//   {"type": "lost_events", "data": {"events": 12}}
+fn add_lost_events(trace: &mut Trace, data: &Value, ids: &mut Ids) {
+    if is_stats_mode(ids) {
+        return;
+    }
+    let event = json!([
+        "track_event",
+        ["name", "lost_events"],
+        ["type", "INSTANT"],
+        ["ts", ids.last_ts],
+        ["track", "bpftrace"],
+        ["events", data["events"]]
+    ]);
+    add_track_event(trace, &event, ids);
+
+    // The next packet is marked as following dropped ones. The trace
+    // processor then throws away the sequence's interned state, so it starts
+    // over with everything interned again.
+    let mut sequence = Sequence::new(ids.sequence.id, &ids.sequence.source);
+    sequence.is_first_packet = false;
+    sequence.is_packet_dropped = true;
+    ids.sequence = sequence;
+}
+
+// printf() and time() output becomes a log message on the bpftrace track
+//   {"type": "printf", "data": "opened /etc/passwd\n"}
+fn add_bpftrace_log(trace: &mut Trace, json_line: &Value, ids: &mut Ids) {
+    if is_stats_mode(ids) {
+        return;
+    }
+    let Some(message) = json_line["data"].as_str().map(str::trim_end) else {
+        return;
+    };
+    if message.is_empty() {
+        return;
+    }
+    let event = json!([
+        "track_event",
+        ["name", json_line["type"]],
+        ["type", "INSTANT"],
+        ["ts", ids.last_ts],
+        ["track", "bpftrace"],
+        ["log", ["INFO", message]]
+    ]);
+    add_track_event(trace, &event, ids);
+}
+
+// Map output e.g. print(@bytes) or the maps printed at exit
+//   {"type": "map", "data": {"@bytes": {"bash": 4096, "sshd": 512}}}
+//   {"type": "map", "data": {"@total": 12}}
+//   {"type": "stats", "data": {"@s": {"count": 3, "average": 40, "total": 120}}}
+// Each key becomes its own counter track e.g. "@bytes[bash]", so repeated
+// prints of the same map show how it changed over time. A stats() value has
+// a counter per stat e.g. "@s count".
+fn add_map_counters(trace: &mut Trace, data: &Value, is_stats: bool, ids: &mut Ids) {
+    if is_stats_mode(ids) {
+        return;
+    }
+    let Some(maps) = data.as_object() else {
+        return;
+    };
+    for (map_name, map) in maps {
+        match map.as_object() {
+            // The keys of a keyed map; an unkeyed stats() value is an object too
+            Some(keys) if !is_stats || keys.values().all(Value::is_object) => {
+                for (key, value) in keys {
+                    add_map_counter(trace, &format!("{map_name}[{key}]"), value, is_stats, ids);
+                }
+            }
+            _ => add_map_counter(trace, map_name, map, is_stats, ids),
+        }
+    }
+}
+
+fn add_map_counter(trace: &mut Trace, name: &str, value: &Value, is_stats: bool, ids: &mut Ids) {
+    if let Some(stats) = value.as_object().filter(|_| is_stats) {
+        for (stat, stat_value) in stats {
+            add_map_counter(trace, &format!("{name} {stat}"), stat_value, false, ids);
+        }
+        return;
+    }
+    // Only integer values can be counters, skip strings, tuples etc.
+    if value.as_i64().is_none() {
+        return;
+    }
+    let event = json!([
+        "track_event",
+        ["name", name],
+        ["type", "COUNTER"],
+        ["ts", ids.last_ts],
+        ["track", name],
+        ["counter_value", value]
+    ]);
+    add_track_event(trace, &event, ids);
+}
+
+// Histogram output e.g. print(@usecs) for @usecs = hist($duration)
+//   {"type": "hist", "data": {"@usecs": [{"min": 4, "max": 7, "count": 3}, ...]}}
+//   {"type": "hist", "data": {"@usecs": {"bash": [...], "sshd": [...]}}}
+// Each histogram becomes an instant event on its own track with a
+// "[min, max)" annotation per bucket holding the bucket's count
+fn add_histogram_summaries(trace: &mut Trace, data: &Value, ids: &mut Ids) {
+    if is_stats_mode(ids) {
+        return;
+    }
+    let Some(maps) = data.as_object() else {
+        return;
+    };
+    for (map_name, map) in maps {
+        if let Some(buckets) = map.as_array() {
+            add_histogram_summary(trace, map_name, None, buckets, ids);
+            continue;
+        }
+        let Some(keys) = map.as_object() else {
+            continue;
+        };
+        for (key, buckets) in keys {
+            if let Some(buckets) = buckets.as_array() {
+                add_histogram_summary(trace, map_name, Some(key), buckets, ids);
+            }
+        }
+    }
+}
+
+fn add_histogram_summary(
+    trace: &mut Trace,
+    map_name: &str,
+    key: Option<&str>,
+    buckets: &[Value],
+    ids: &mut Ids,
+) {
+    let name = match key {
+        Some(key) => format!("{map_name}[{key}]"),
+        None => map_name.to_string(),
+    };
+    let mut event = vec![
+        json!("track_event"),
+        json!(["name", name]),
+        json!(["type", "INSTANT"]),
+        json!(["ts", ids.last_ts]),
+        json!(["track", name]),
+    ];
+    if key.is_some() {
+        event.push(json!(["track_parent", map_name]));
+    }
+
+    let mut total = 0;
+    for bucket in buckets {
+        let count = bucket["count"].as_u64().unwrap_or(0);
+        total += count;
+        // The bucket max is inclusive, print it the way bpftrace does
+        let label = match (bucket["min"].as_i64(), bucket["max"].as_i64()) {
+            (Some(min), Some(max)) if min == max => format!("[{min}]"),
+            (Some(min), Some(max)) => format!("[{min}, {})", max + 1),
+            (Some(min), None) => format!("[{min}, ...)"),
+            (None, Some(max)) => format!("(..., {})", max + 1),
+            (None, None) => continue,
+        };
+        event.push(json!([label, count]));
+    }
+    event.push(json!(["count", total]));
+
+    add_track_event(trace, &Value::from(event), ids);
 }
 
 //  Example call stack samples
@@ -446,29 +1460,28 @@ fn add_call_stack_sample(trace: &mut Trace, data: &Value, ids: &mut Ids) {
 
     let pid = event["pid"].as_u64().unwrap();
     let tid = event["tid"].as_u64().unwrap();
-    if get_uuid_for_pid_tid(&pid, &tid, &ids).is_none() {
+    if get_uuid_for_pid_tid(&pid, &tid, ids).is_none() {
         // Track descriptor doesn't exist, let's make one
         add_track_descriptor_thread(trace, &pid, &tid, event["thread_name"].as_str(), ids);
     }
 
     let mut packet = TracePacket::new();
-    packet.optional_trusted_packet_sequence_id =
-        Some(trace_packet::Optional_trusted_packet_sequence_id::TrustedPacketSequenceId(1));
+    packet.optional_trusted_packet_sequence_id = Some(
+        trace_packet::Optional_trusted_packet_sequence_id::TrustedPacketSequenceId(ids.sequence.id),
+    );
 
     let mut perf_sample = PerfSample::new();
     perf_sample.cpu_mode = Some(profiling::CpuMode::MODE_USER.into());
 
-    set_sequence_flags(&mut packet);
+    set_sequence_flags(&mut packet, &mut ids.sequence);
 
     let mut interned_data = InternedData::new();
 
-    unsafe {
-        if IS_FIRST_CALL_SAMPLE {
-            let mut dummy_mapping = Mapping::new();
-            dummy_mapping.iid = Some(1);
-            interned_data.mappings.push(dummy_mapping);
-            IS_FIRST_CALL_SAMPLE = false;
-        }
+    if ids.sequence.is_first_call_sample {
+        let mut dummy_mapping = Mapping::new();
+        dummy_mapping.iid = Some(1);
+        interned_data.mappings.push(dummy_mapping);
+        ids.sequence.is_first_call_sample = false;
     }
 
     perf_sample.pid = Some(event["pid"].as_u64().unwrap() as u32);
@@ -478,39 +1491,37 @@ fn add_call_stack_sample(trace: &mut Trace, data: &Value, ids: &mut Ids) {
         perf_sample.cpu = Some(event["cpu"].as_u64().unwrap() as u32);
     }
 
-    let callstack_iid: Option<u64>;
-
-    if event.contains_key("kstack") {
-        if event.contains_key("ustack") {
-            callstack_iid = process_call_stacks(
-                &mut interned_data,
-                ids,
-                event["kstack"].as_str().unwrap(),
-                event["ustack"].as_str(),
-            );
-        } else {
-            callstack_iid = process_call_stacks(
-                &mut interned_data,
-                ids,
-                event["kstack"].as_str().unwrap(),
-                None,
-            );
-        }
-    } else {
-        callstack_iid = process_call_stacks(
-            &mut interned_data,
-            ids,
-            event["ustack"].as_str().unwrap(),
-            None,
-        );
-    }
-
-    perf_sample.callstack_iid = callstack_iid;
+    perf_sample.callstack_iid = process_call_stacks(
+        &mut interned_data,
+        ids,
+        event.get("kstack").and_then(Value::as_str),
+        event.get("ustack").and_then(Value::as_str),
+    );
 
     packet.timestamp = Some(event["ts"].as_u64().unwrap());
+    packet.timestamp_clock_id = ids.clock_id;
+    update_ts_range(event["ts"].as_u64().unwrap(), ids);
     packet.interned_data = Some(interned_data).into();
     packet.data = Some(trace_packet::Data::PerfSample(perf_sample));
     trace.packet.push(packet);
+
+    add_profiled_frame_symbols(trace, ids);
+}
+
+// Line info from offline symbolization is emitted after the sample whose
+// interned data introduced the frames it refers to
+fn add_profiled_frame_symbols(trace: &mut Trace, ids: &mut Ids) {
+    for frame_symbols in ids.frame_symbols.drain(..) {
+        let mut packet = TracePacket::new();
+        packet.optional_trusted_packet_sequence_id = Some(
+            trace_packet::Optional_trusted_packet_sequence_id::TrustedPacketSequenceId(
+                ids.sequence.id,
+            ),
+        );
+        set_sequence_flags(&mut packet, &mut ids.sequence);
+        packet.data = Some(trace_packet::Data::ProfiledFrameSymbols(frame_symbols));
+        trace.packet.push(packet);
+    }
 }
 
 fn get_uuid_for_name(name: &str, ids: &Ids) -> Option<u64> {
@@ -540,27 +1551,23 @@ fn add_track_descriptor_thread(
     thread_name: Option<&str>,
     ids: &mut Ids,
 ) -> u64 {
-    let uuid: u64 = gen_uuid();
+    let uuid: u64 = gen_uuid(ids);
     let mut packet = TracePacket::new();
 
     if !ids.pid_tid_uuids.contains_key(pid) {
-        ids.pid_tid_uuids.insert(pid.clone(), HashMap::new());
+        ids.pid_tid_uuids.insert(*pid, HashMap::new());
     }
 
-    let pid_map = ids.pid_tid_uuids.get_mut(&pid);
-    pid_map.unwrap().insert(tid.clone(), uuid.clone());
+    let pid_map = ids.pid_tid_uuids.get_mut(pid);
+    pid_map.unwrap().insert(*tid, uuid);
 
     let mut track_descriptor = TrackDescriptor::new();
-    track_descriptor.uuid = Some(uuid.clone());
+    track_descriptor.uuid = Some(uuid);
 
     let mut thread_descriptor = ThreadDescriptor::new();
-    thread_descriptor.pid = Some(pid.clone() as i32);
-    thread_descriptor.tid = Some(tid.clone() as i32);
-    thread_descriptor.thread_name = if thread_name.is_none() {
-        Some("unknown".to_string())
-    } else {
-        Some(thread_name.unwrap().to_string())
-    };
+    thread_descriptor.pid = Some(*pid as i32);
+    thread_descriptor.tid = Some(*tid as i32);
+    thread_descriptor.thread_name = Some(thread_name.unwrap_or("unknown").to_string());
 
     track_descriptor.thread = Some(thread_descriptor).into();
     packet.data = Some(trace_packet::Data::TrackDescriptor(track_descriptor));
@@ -570,69 +1577,128 @@ fn add_track_descriptor_thread(
     uuid
 }
 
-fn gen_uuid() -> u64 {
+fn gen_uuid(ids: &Ids) -> u64 {
     unsafe {
         TRACK_DESCRIPTOR_UUID += 1;
+        while ids.reserved_uuids.contains(&{ TRACK_DESCRIPTOR_UUID }) {
+            TRACK_DESCRIPTOR_UUID += 1;
+        }
         TRACK_DESCRIPTOR_UUID
     }
 }
 
-fn gen_flow_id() -> u64 {
+fn gen_flow_id(ids: &Ids) -> u64 {
     unsafe {
         FLOW_UUID += 1;
+        while ids.reserved_flow_ids.contains(&{ FLOW_UUID }) {
+            FLOW_UUID += 1;
+        }
         FLOW_UUID
     }
 }
 
-fn set_sequence_flags(packet: &mut TracePacket) {
-    unsafe {
-        if IS_FIRST_PACKET {
+fn set_sequence_flags(packet: &mut TracePacket, sequence: &mut Sequence) {
+    if sequence.is_first_packet || sequence.is_packet_dropped {
+        if sequence.is_first_packet {
             packet.first_packet_on_sequence = Some(true);
-            packet.previous_packet_dropped = Some(true);
-            packet.sequence_flags = Some(
-                (trace_packet::SequenceFlags::SEQ_INCREMENTAL_STATE_CLEARED as u32)
-                    | (trace_packet::SequenceFlags::SEQ_NEEDS_INCREMENTAL_STATE as u32),
-            );
-            IS_FIRST_PACKET = false;
-        } else {
-            packet.sequence_flags =
-                Some(trace_packet::SequenceFlags::SEQ_NEEDS_INCREMENTAL_STATE as u32)
         }
+        packet.previous_packet_dropped = Some(true);
+        packet.sequence_flags = Some(
+            (trace_packet::SequenceFlags::SEQ_INCREMENTAL_STATE_CLEARED as u32)
+                | (trace_packet::SequenceFlags::SEQ_NEEDS_INCREMENTAL_STATE as u32),
+        );
+        sequence.is_first_packet = false;
+        sequence.is_packet_dropped = false;
+    } else {
+        packet.sequence_flags =
+            Some(trace_packet::SequenceFlags::SEQ_NEEDS_INCREMENTAL_STATE as u32)
+    }
+}
+
+// Makes the source's sequence the current one, starting a new sequence the
+// first time a source is seen
+// Each file is its own source so gets its own sequence; threads are shared by
+// pid/tid and the timestamps are ordered by trace processor
+fn start_input(
+    trace: &mut Trace,
+    ids: &mut Ids,
+    options: &args::Options,
+    index: usize,
+    input_file: &str,
+) {
+    ids.input_source = index.to_string();
+    switch_source(trace, &index.to_string(), ids);
+    if options.namespace_tracks {
+        let file_name = Path::new(input_file).file_name().unwrap_or_default();
+        ids.track_namespace = Some(file_name.to_string_lossy().to_string());
     }
 }
 
-fn get_string_id(s: &str, ids: &mut Ids) -> (u64, bool) {
-    let mut added = false;
-    if !ids.string_ids.contains_key(s) {
-        ids.interned_data_id += 1;
-        ids.string_ids.insert(s.to_string(), ids.interned_data_id);
-        added = true;
+fn switch_source(trace: &mut Trace, source: &str, ids: &mut Ids) {
+    if ids.sequence.source == source {
+        return;
     }
+    let (sequence, is_new) = match ids.sequences.remove(source) {
+        Some(sequence) => (sequence, false),
+        None => {
+            ids.next_sequence_id += 1;
+            (Sequence::new(ids.next_sequence_id - 1, source), true)
+        }
+    };
+    let previous_sequence = mem::replace(&mut ids.sequence, sequence);
+    ids.sequences
+        .insert(previous_sequence.source.clone(), previous_sequence);
+
+    // The tai clock is sequence scoped so each sequence needs a snapshot
+    if is_new {
+        add_clock_snapshot(trace, ids);
+    }
+}
 
-    (ids.string_ids[s], added)
+fn get_string_id(s: &str, field: StringField, ids: &mut Ids) -> (u64, bool) {
+    let key = (field, s.to_string());
+    if let Some(string_id) = ids.sequence.string_ids.get(&key) {
+        return (*string_id, false);
+    }
+    ids.sequence.interned_data_id += 1;
+    ids.sequence
+        .string_ids
+        .insert(key, ids.sequence.interned_data_id);
+    (ids.sequence.interned_data_id, true)
 }
 
-// If there is a second stack it's always the user stack
+// The kernel stack goes on top of the user stack
 fn process_call_stacks(
     interned_data: &mut InternedData,
     ids: &mut Ids,
-    stack1str: &str,
-    stack2str: Option<&str>,
+    kstack: Option<&str>,
+    ustack: Option<&str>,
 ) -> Option<u64> {
-    let stack1 = util::parse_stack_str(&stack1str);
-    if stack2str.is_some() {
-        let stack2 = util::parse_stack_str(stack2str.unwrap());
-        let concat_stack = [stack1, stack2].concat();
-        if concat_stack.len() == 0 {
-            return None;
-        }
-        return Some(add_call_stack(&concat_stack, interned_data, ids));
-    } else {
-        if stack1.len() == 0 {
-            return None;
+    let mut stack = Vec::new();
+    if let Some(kstack) = kstack {
+        for frame in util::parse_stack_str(kstack) {
+            stack.push(symbolize_kernel_frame(frame, ids));
         }
-        return Some(add_call_stack(&stack1, interned_data, ids));
     }
+    if let Some(ustack) = ustack {
+        stack.extend(util::parse_stack_str(ustack));
+    }
+
+    if stack.is_empty() {
+        return None;
+    }
+    Some(add_call_stack(&stack, interned_data, ids))
+}
+
+// Raw addresses become symbol+off function names of their frames; there's
+// nothing in Perfetto's call stacks that refers to InternedData.kernel_symbols
+fn symbolize_kernel_frame(frame: String, ids: &Ids) -> String {
+    let Some(kallsyms) = &ids.kallsyms else {
+        return frame;
+    };
+    symbolize::parse_frame_address(&frame)
+        .and_then(|frame_address| kallsyms.resolve(frame_address.address))
+        .unwrap_or(frame)
 }
 
 fn add_call_stack(stack: &Vec<String>, interned_data: &mut InternedData, ids: &mut Ids) -> u64 {
@@ -640,12 +1706,12 @@ fn add_call_stack(stack: &Vec<String>, interned_data: &mut InternedData, ids: &m
     for frame in stack {
         stack_ids.push(add_stack_frame(frame, interned_data, ids));
     }
-    if !ids.call_stack_ids.contains_key(&stack_ids) {
-        ids.interned_data_id += 1;
-        let cs_id = ids.interned_data_id;
-        ids.call_stack_ids.insert(stack_ids.clone(), cs_id);
+    if !ids.sequence.call_stack_ids.contains_key(&stack_ids) {
+        ids.sequence.interned_data_id += 1;
+        let cs_id = ids.sequence.interned_data_id;
+        ids.sequence.call_stack_ids.insert(stack_ids.clone(), cs_id);
         let mut callstack = Callstack::new();
-        callstack.iid = Some(cs_id.clone());
+        callstack.iid = Some(cs_id);
         // Perfetto wants bottom frame first
         for x in stack_ids.into_iter().rev() {
             callstack.frame_ids.push(x);
@@ -653,27 +1719,712 @@ fn add_call_stack(stack: &Vec<String>, interned_data: &mut InternedData, ids: &m
         interned_data.callstacks.push(callstack);
         return cs_id;
     }
-    return ids.call_stack_ids[&stack_ids];
+    ids.sequence.call_stack_ids[&stack_ids]
 }
 
 fn add_stack_frame(frame: &String, interned_data: &mut InternedData, ids: &mut Ids) -> u64 {
-    let string_id_pair = get_string_id(frame, ids);
+    if let Some(frame_id) = ids.sequence.frame_ids.get(frame) {
+        return *frame_id;
+    }
+
+    let symbol = ids.symbolizer.symbolize(frame);
+    let function_name = symbol.as_ref().map_or(frame.as_str(), |s| s.name.as_str());
+    let function_name_id = add_function_name(function_name, interned_data, ids);
+
+    let mut f = Frame::new();
+    ids.sequence.interned_data_id += 1;
+    let frame_id = ids.sequence.interned_data_id;
+    f.iid = Some(frame_id);
+    f.function_name_id = Some(function_name_id);
+    f.mapping_id = Some(1);
+
+    if let Some(symbol) = symbol {
+        f.mapping_id = Some(add_mapping(&symbol.mapping_path, interned_data, ids));
+        f.rel_pc = Some(symbol.rel_pc);
+
+        if !symbol.lines.is_empty() {
+            let mut frame_symbols = ProfiledFrameSymbols::new();
+            frame_symbols.frame_iid = Some(frame_id);
+            for line in symbol.lines {
+                frame_symbols.function_name_id.push(add_function_name(
+                    &line.function_name,
+                    interned_data,
+                    ids,
+                ));
+                let source_file = line.source_file.unwrap_or_default();
+                let string_id_pair = get_string_id(&source_file, StringField::SourcePath, ids);
+                if string_id_pair.1 {
+                    let mut is = InternedString::new();
+                    is.iid = Some(string_id_pair.0);
+                    is.str = Some(source_file.as_bytes().to_vec());
+                    interned_data.source_paths.push(is);
+                }
+                frame_symbols.file_name_id.push(string_id_pair.0);
+                frame_symbols
+                    .line_number
+                    .push(line.line_number.unwrap_or_default());
+            }
+            ids.frame_symbols.push(frame_symbols);
+        }
+    }
+
+    interned_data.frames.push(f);
+    ids.sequence.frame_ids.insert(frame.clone(), frame_id);
+    frame_id
+}
+
+fn add_function_name(name: &str, interned_data: &mut InternedData, ids: &mut Ids) -> u64 {
+    let string_id_pair = get_string_id(name, StringField::FunctionName, ids);
 
     if string_id_pair.1 {
         let mut is = InternedString::new();
         is.iid = Some(string_id_pair.0);
-        is.str = Some(frame.as_bytes().to_vec());
+        is.str = Some(name.as_bytes().to_vec());
         interned_data.function_names.push(is);
+    }
+
+    string_id_pair.0
+}
+
+fn add_mapping(path: &str, interned_data: &mut InternedData, ids: &mut Ids) -> u64 {
+    if let Some(mapping_id) = ids.sequence.mapping_ids.get(path) {
+        return *mapping_id;
+    }
+
+    let mut mapping = Mapping::new();
+    // Perfetto joins the path components back together with '/'
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        let string_id_pair = get_string_id(component, StringField::MappingPath, ids);
+        if string_id_pair.1 {
+            let mut is = InternedString::new();
+            is.iid = Some(string_id_pair.0);
+            is.str = Some(component.as_bytes().to_vec());
+            interned_data.mapping_paths.push(is);
+        }
+        mapping.path_string_ids.push(string_id_pair.0);
+    }
+
+    ids.sequence.interned_data_id += 1;
+    mapping.iid = Some(ids.sequence.interned_data_id);
+    interned_data.mappings.push(mapping);
+    ids.sequence
+        .mapping_ids
+        .insert(path.to_string(), ids.sequence.interned_data_id);
+
+    ids.sequence.interned_data_id
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    // The uuid counters are statics so conversions can't run in parallel
+    static CONVERSION: Mutex<()> = Mutex::new(());
+
+    // Converts bpftrace's JSON output like main() does for stdin
+    pub(crate) fn convert(lines: &[Value], args: &[&str]) -> Trace {
+        convert_inputs(&[lines], args)
+    }
+
+    // Like convert() for the input files in args, with the lines of each.
+    // The files aren't read.
+    fn convert_inputs(inputs: &[&[Value]], args: &[&str]) -> Trace {
+        let _conversion = CONVERSION.lock().unwrap_or_else(|e| e.into_inner());
+        let args: Vec<String> = ["btetto"]
+            .iter()
+            .chain(args)
+            .map(|a| a.to_string())
+            .collect();
+        let options = args::parse_args(&args);
+        unsafe {
+            TRACK_DESCRIPTOR_UUID = 1;
+            FLOW_UUID = 1;
+        }
+
+        let mut trace = Trace::new();
+        let existing_trace = options.merge_into.as_deref().map(merge::read_trace);
+        let mut ids = new_ids(&options, existing_trace.as_ref());
+        start_trace(&mut trace, &mut ids, existing_trace.as_ref());
+        for (index, lines) in inputs.iter().enumerate() {
+            if let Some(input_file) = options.input_files.get(index) {
+                start_input(&mut trace, &mut ids, &options, index, input_file);
+            }
+            for line in lines.iter() {
+                parse_output(&mut trace, line, &mut ids);
+                write_trigger_snapshots(&trace, &mut ids, &options, &args, None, false);
+                rotate_if_due(&mut trace, &mut ids, &options, &args, None);
+            }
+        }
+        add_held_records(&mut trace, &mut ids);
+        if let Some(sampler) = ids.sampler.as_mut() {
+            let dropped_counts = sampler.finish();
+            add_dropped_counters(&mut trace, dropped_counts, &mut ids);
+        }
+        write_trigger_snapshots(&trace, &mut ids, &options, &args, None, true);
+        if let Some(ring_buffer) = ids.ring_buffer.as_mut() {
+            if let Some(first_ts) = ring_buffer.window(&mut trace, ids.last_ts) {
+                ids.first_ts = Some(first_ts);
+            }
+        }
+        finish_trace(&mut trace, &mut ids, &args, None, existing_trace.as_ref());
+        if let Some(existing_trace) = existing_trace {
+            let mut merged_trace = existing_trace.trace;
+            merged_trace.packet.append(&mut trace.packet);
+            trace = merged_trace;
+        }
+        trace
+    }
+
+    pub(crate) fn instant(name: &str, ts: u64) -> Value {
+        json!({"type": "value", "data": ["track_event", ["name", name], ["type", "INSTANT"],
+            ["ts", ts], ["pid", 1], ["tid", 1]]})
+    }
+
+    pub(crate) fn call_stack(ts: u64, tid: u64, ustack: &str) -> Value {
+        json!({"type": "value", "data": ["call_stack", ["ts", ts], ["pid", 10], ["tid", tid],
+            ["thread_name", "bash"], ["ustack", ustack]]})
+    }
+
+    // The name and args of every track event, as a trace reader sees them
+    pub(crate) fn track_events(trace: &Trace) -> Vec<(String, serde_json::Map<String, Value>)> {
+        let mut sequence_state = interned::SequenceState::default();
+        let mut events = Vec::new();
+        for packet in &trace.packet {
+            let interned = sequence_state.update(packet);
+            if packet.has_track_event() {
+                let track_event = packet.track_event();
+                events.push((
+                    interned.get_event_name(track_event),
+                    interned.get_event_args(track_event),
+                ));
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn lost_events_restart_the_sequence() {
+        let lines = [
+            instant("a", 1),
+            call_stack(2, 11, "\n f0+1\n main+4\n"),
+            json!({"type": "lost_events", "data": {"events": 12}}),
+            instant("a", 5),
+            call_stack(6, 11, "\n f0+1\n main+4\n"),
+        ];
+        let trace = convert(&lines, &[]);
+
+        let events = track_events(&trace);
+        let names: Vec<&str> = events.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["a", "lost_events", "a"]);
+        assert_eq!(events[1].1["events"], 12);
+
+        // The packet after the marker follows dropped ones and clears the
+        // interned state
+        let marker = trace
+            .packet
+            .iter()
+            .position(|packet| packet.has_track_event() && packet.timestamp() == 2)
+            .unwrap();
+        let next_packet = &trace.packet[marker + 1];
+        assert_eq!(
+            next_packet.trusted_packet_sequence_id(),
+            trace.packet[marker].trusted_packet_sequence_id()
+        );
+        assert!(next_packet.previous_packet_dropped());
+        assert!(interned::is_incremental_state_cleared(next_packet));
+        assert!(!next_packet.first_packet_on_sequence());
+
+        // The stack is interned again after it
+        let mut sequence_state = interned::SequenceState::default();
+        let mut stacks = Vec::new();
+        for packet in &trace.packet {
+            let interned = sequence_state.update(packet);
+            if packet.has_perf_sample() {
+                stacks.push(interned.get_callstack_names(packet.perf_sample().callstack_iid()));
+            }
+        }
+        assert_eq!(stacks, [["main+4", "f0+1"], ["main+4", "f0+1"]]);
+    }
+
+    #[test]
+    fn strings_are_interned_per_field() {
+        let (frame, _) = symbolize::tests::main_frame();
+        let exe = env::current_exe().unwrap();
+        let exe = exe.to_str().unwrap();
+        let mapping_component = exe.split('/').find(|c| !c.is_empty()).unwrap();
+        // The same strings as the function name and the path go in first
+        let lines = [
+            json!({"type": "value", "data": ["track_event", ["name", "main"], ["type", "INSTANT"],
+                ["ts", 1], ["pid", 10], ["tid", 11], ["dir", mapping_component]]}),
+            call_stack(2, 11, &format!("\n {frame}\n")),
+        ];
+        let trace = convert(&lines, &[]);
+
+        let mut sequence_state = interned::SequenceState::default();
+        for packet in &trace.packet {
+            let interned = sequence_state.update(packet);
+            if packet.has_perf_sample() {
+                let callstack_iid = packet.perf_sample().callstack_iid();
+                assert_eq!(interned.get_callstack_names(callstack_iid), ["main"]);
+                let frame = &interned.frames[&interned.callstacks[&callstack_iid][0]];
+                assert_eq!(
+                    interned.get_mapping_path(frame.mapping_id()).as_deref(),
+                    Some(exe)
+                );
+            }
+        }
+    }
 
-        let mut f = Frame::new();
-        ids.interned_data_id += 1;
-        f.iid = Some(ids.interned_data_id);
-        f.function_name_id = Some(string_id_pair.0);
-        f.mapping_id = Some(1);
-        interned_data.frames.push(f);
-        return ids.interned_data_id;
+    #[test]
+    fn rotated_files_are_self_contained() {
+        let output_file = env::temp_dir().join(format!("btetto-test-{}.pftrace", process::id()));
+        let output_file = output_file.to_str().unwrap();
+        let lines: Vec<Value> = (0..8)
+            .map(|i| instant(&format!("e{i}"), 1_000_000_000 + i * 500_000_000))
+            .collect();
+        let last_part = convert(&lines, &["-o", output_file, "--rotate-every", "1s"]);
+
+        let mut parts = Vec::new();
+        for num in 1.. {
+            let part_file = numbered_output_file(output_file, "-", num);
+            if !Path::new(&part_file).exists() {
+                break;
+            }
+            parts.push(merge::read_trace(&part_file).trace);
+            fs::remove_file(&part_file).unwrap();
+        }
+        parts.push(last_part);
+        assert!(parts.len() >= 3);
+
+        let mut names = Vec::new();
+        for part in &parts {
+            // Every event's name and track are in the same file
+            let track_uuids: HashSet<u64> = part
+                .packet
+                .iter()
+                .filter(|packet| packet.has_track_descriptor())
+                .map(|packet| packet.track_descriptor().uuid())
+                .collect();
+            for packet in part.packet.iter().filter(|packet| packet.has_track_event()) {
+                assert!(track_uuids.contains(&packet.track_event().track_uuid()));
+            }
+            let events = track_events(part);
+            assert!(!events.is_empty());
+            names.extend(events.into_iter().map(|(name, _)| name));
+        }
+        let expected_names: Vec<String> = (0..8).map(|i| format!("e{i}")).collect();
+        assert_eq!(names, expected_names);
     }
 
-    // The frame id is always one greater than the string id
-    return string_id_pair.0 + 1;
+    // The btetto data source's legacy config, as key: value pairs
+    fn trace_config(trace: &Trace) -> HashMap<String, String> {
+        let configs: Vec<&TraceConfig> = trace
+            .packet
+            .iter()
+            .filter(|packet| packet.has_trace_config())
+            .map(|packet| packet.trace_config())
+            .collect();
+        assert_eq!(configs.len(), 1);
+        let data_source = &configs[0].data_sources[0].config;
+        assert_eq!(data_source.name(), "btetto");
+        data_source
+            .legacy_config()
+            .lines()
+            .filter_map(|line| line.split_once(": "))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn trigger_snapshots_have_a_trace_config() {
+        let output_file =
+            env::temp_dir().join(format!("btetto-test-{}-config.pftrace", process::id()));
+        let output_file = output_file.to_str().unwrap();
+        let lines = [
+            instant("a", 1_000),
+            json!({"type": "value", "data": ["trigger", "oom"]}),
+            instant("b", 2_000),
+        ];
+        let trace = convert(&lines, &["-o", output_file]);
+        let snapshot_file = numbered_output_file(output_file, "_trigger_", 1);
+        let snapshot = merge::read_trace(&snapshot_file).trace;
+        fs::remove_file(&snapshot_file).unwrap();
+
+        for trace in [&trace, &snapshot] {
+            let config = trace_config(trace);
+            assert_eq!(
+                config["hostname"],
+                util::read_sys_value("/proc/sys/kernel/hostname")
+            );
+            assert_eq!(
+                config["kernel_release"],
+                util::read_sys_value("/proc/sys/kernel/osrelease")
+            );
+            assert_eq!(config["btetto_version"], env!("CARGO_PKG_VERSION"));
+            assert_eq!(config["cmdline"], format!("btetto -o {output_file}"));
+        }
+    }
+
+    #[test]
+    fn trigger_is_on_a_sequence_with_the_clock() {
+        let output_file =
+            env::temp_dir().join(format!("btetto-test-{}-clock.pftrace", process::id()));
+        let output_file = output_file.to_str().unwrap();
+        let lines = [
+            instant("a", 1_000),
+            instant("oom", 2_000),
+            instant("b", 3_000),
+        ];
+        convert(
+            &lines,
+            &[
+                "-o",
+                output_file,
+                "--clock",
+                "tai",
+                "--trigger-on",
+                "name=oom",
+            ],
+        );
+        let snapshot_file = numbered_output_file(output_file, "_trigger_", 1);
+        let snapshot = merge::read_trace(&snapshot_file).trace;
+        fs::remove_file(&snapshot_file).unwrap();
+
+        let trigger = snapshot.packet.iter().find(|p| p.has_trigger()).unwrap();
+        assert_eq!(trigger.trigger().trigger_name(), "oom");
+        assert_eq!(trigger.timestamp(), 2_000);
+        assert_eq!(
+            trigger.timestamp_clock_id,
+            Some(clock::get_clock_id(args::Clock::Tai))
+        );
+        let event = snapshot
+            .packet
+            .iter()
+            .find(|p| p.has_track_event())
+            .unwrap();
+        assert_eq!(
+            trigger.trusted_packet_sequence_id(),
+            event.trusted_packet_sequence_id()
+        );
+        assert!(snapshot.packet.iter().any(|p| p.has_clock_snapshot()
+            && p.trusted_packet_sequence_id() == trigger.trusted_packet_sequence_id()));
+    }
+
+    // The trigger name and event names of each --trigger-on snapshot written
+    fn trigger_snapshots(output_file: &str) -> Vec<(String, Vec<String>)> {
+        let mut snapshots = Vec::new();
+        for num in 1.. {
+            let snapshot_file = numbered_output_file(output_file, "_trigger_", num);
+            if !Path::new(&snapshot_file).exists() {
+                break;
+            }
+            let snapshot = merge::read_trace(&snapshot_file).trace;
+            fs::remove_file(&snapshot_file).unwrap();
+            let trigger = snapshot.packet.iter().find(|p| p.has_trigger()).unwrap();
+            let names = track_events(&snapshot).into_iter().map(|(name, _)| name);
+            snapshots.push((
+                trigger.trigger().trigger_name().to_string(),
+                names.collect(),
+            ));
+        }
+        snapshots
+    }
+
+    #[test]
+    fn trigger_snapshot_windows() {
+        let output_file =
+            env::temp_dir().join(format!("btetto-test-{}-window.pftrace", process::id()));
+        let output_file = output_file.to_str().unwrap();
+        let lines: Vec<Value> = (0..10)
+            .map(|i| match i {
+                3 | 7 => instant("oom", i * 1_000_000_000),
+                _ => instant(&format!("e{i}"), i * 1_000_000_000),
+            })
+            .collect();
+        let args = [
+            "-o",
+            output_file,
+            "--trigger-on",
+            "name=oom",
+            "--trigger-before",
+            "1s",
+            "--trigger-after",
+            "2s",
+        ];
+        convert(&lines, &args);
+
+        // Numbered in order, the bounds are included and the last one gets
+        // what's left of its window
+        assert_eq!(
+            trigger_snapshots(output_file),
+            [
+                ("oom".to_string(), vec!["e2", "oom", "e4", "e5"]),
+                ("oom".to_string(), vec!["e6", "oom", "e8", "e9"]),
+            ]
+            .map(|(name, names)| (name, names.iter().map(|n| n.to_string()).collect()))
+        );
+    }
+
+    #[test]
+    fn rotation_waits_for_pending_triggers() {
+        let output_file =
+            env::temp_dir().join(format!("btetto-test-{}-rotate.pftrace", process::id()));
+        let output_file = output_file.to_str().unwrap();
+        let lines: Vec<Value> = (0..8)
+            .map(|i| match i {
+                2 => instant("oom", i * 500_000_000),
+                _ => instant(&format!("e{i}"), i * 500_000_000),
+            })
+            .collect();
+        let args = [
+            "-o",
+            output_file,
+            "--rotate-every",
+            "1s",
+            "--trigger-on",
+            "name=oom",
+            "--trigger-before",
+            "1s",
+            "--trigger-after",
+            "2s",
+        ];
+        convert(&lines, &args);
+        for num in 1.. {
+            let part_file = numbered_output_file(output_file, "-", num);
+            if !Path::new(&part_file).exists() {
+                break;
+            }
+            fs::remove_file(&part_file).unwrap();
+        }
+
+        let names = ["e0", "e1", "oom", "e3", "e4", "e5", "e6"];
+        assert_eq!(
+            trigger_snapshots(output_file),
+            [("oom".to_string(), names.map(str::to_string).to_vec())]
+        );
+    }
+
+    // The sequence id and sequence flags of each packet that is on one
+    fn sequences(trace: &Trace) -> Vec<(u32, u32)> {
+        trace
+            .packet
+            .iter()
+            .filter(|p| p.trusted_packet_sequence_id() != 0)
+            .map(|p| (p.trusted_packet_sequence_id(), p.sequence_flags()))
+            .collect()
+    }
+
+    #[test]
+    fn each_input_has_its_own_sequence() {
+        let input = [instant("a", 1_000), instant("b", 2_000)];
+        // The same file given twice is still two inputs
+        let trace = convert_inputs(&[&input, &input], &["a.json", "a.json"]);
+
+        let cleared = trace_packet::SequenceFlags::SEQ_INCREMENTAL_STATE_CLEARED as u32;
+        let sequences = sequences(&trace);
+        let mut sequence_ids: Vec<u32> = sequences.iter().map(|(id, _)| *id).collect();
+        sequence_ids.dedup();
+        assert_eq!(sequence_ids.len(), 2);
+        for sequence_id in sequence_ids {
+            let (_, flags) = sequences.iter().find(|(id, _)| *id == sequence_id).unwrap();
+            assert!(flags & cleared != 0);
+        }
+        // Both inputs' events are there, each with its name interned on its
+        // own sequence
+        let names: Vec<String> = track_events(&trace)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, ["a", "b", "a", "b"]);
+    }
+
+    #[test]
+    fn each_cpu_has_its_own_sequence() {
+        let event = |name: &str, ts: u64, cpu: u64| {
+            json!({"type": "value", "data": ["track_event", ["name", name], ["type", "INSTANT"],
+                ["ts", ts], ["pid", 1], ["tid", 1], ["cpu", cpu]]})
+        };
+        let lines = [
+            event("a", 1_000, 0),
+            event("a", 2_000, 1),
+            event("b", 3_000, 0),
+        ];
+        let trace = convert(&lines, &["--sequence-per-cpu"]);
+
+        let event_sequences: Vec<u32> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.trusted_packet_sequence_id())
+            .collect();
+        assert_ne!(event_sequences[0], event_sequences[1]);
+        assert_eq!(event_sequences[2], event_sequences[0]);
+        // Each sequence starts cleared, the cpu ones with their clock snapshot
+        let cleared = trace_packet::SequenceFlags::SEQ_INCREMENTAL_STATE_CLEARED as u32;
+        let sequences = sequences(&trace);
+        for sequence_id in &event_sequences[..2] {
+            let (_, flags) = sequences.iter().find(|(id, _)| id == sequence_id).unwrap();
+            assert!(flags & cleared != 0);
+        }
+        let names: Vec<String> = track_events(&trace)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, ["a", "a", "b"]);
+    }
+
+    // (track name, ts, value) of every counter
+    fn counters(trace: &Trace) -> Vec<(String, u64, i64)> {
+        let mut track_names = HashMap::new();
+        let mut counters = Vec::new();
+        for packet in &trace.packet {
+            if packet.has_track_descriptor() {
+                let track_descriptor = packet.track_descriptor();
+                track_names.insert(track_descriptor.uuid(), track_descriptor.name().to_string());
+            }
+            if packet.has_track_event() && packet.track_event().has_counter_value() {
+                let track_event = packet.track_event();
+                counters.push((
+                    track_names[&track_event.track_uuid()].clone(),
+                    packet.timestamp(),
+                    track_event.counter_value(),
+                ));
+            }
+        }
+        counters
+    }
+
+    #[test]
+    fn map_counters() {
+        let lines = [
+            instant("a", 1_000),
+            json!({"type": "map", "data": {"@bytes": {"bash": 4096, "sshd": 512}}}),
+            json!({"type": "map", "data": {"@total": 12, "@comm": {"1": "bash"}}}),
+            instant("a", 2_000),
+            json!({"type": "stats", "data": {"@s": {"count": 3, "average": 40, "total": 120}}}),
+            json!({"type": "stats", "data": {"@k": {"bash": {"count": 1, "average": 5, "total": 5}}}}),
+        ];
+        let trace = convert(&lines, &[]);
+
+        let counters = counters(&trace);
+        let expected = [
+            ("@bytes[bash]", 1_000, 4096),
+            ("@bytes[sshd]", 1_000, 512),
+            ("@total", 1_000, 12),
+            ("@s average", 2_000, 40),
+            ("@s count", 2_000, 3),
+            ("@s total", 2_000, 120),
+            ("@k[bash] average", 2_000, 5),
+            ("@k[bash] count", 2_000, 1),
+            ("@k[bash] total", 2_000, 5),
+        ]
+        .map(|(name, ts, value)| (name.to_string(), ts, value));
+        assert_eq!(counters, expected);
+    }
+
+    #[test]
+    fn histogram_summaries() {
+        let buckets = json!([
+            {"max": -1, "count": 1},
+            {"min": 4, "max": 7, "count": 3},
+            {"min": 8, "max": 8, "count": 1},
+            {"min": 1024, "max": 2047, "count": 0},
+            {"min": 100, "count": 2},
+        ]);
+        let lines = [
+            instant("a", 1_000),
+            json!({"type": "hist", "data": {"@usecs": buckets}}),
+            json!({"type": "lhist", "data": {"@ms": {"bash": [{"min": 0, "max": 9, "count": 4}]}}}),
+        ];
+        let trace = convert(&lines, &[]);
+
+        let events = track_events(&trace);
+        assert_eq!(events[1].0, "@usecs");
+        // Annotations are read back sorted by name
+        let annotations: Vec<(&str, u64)> = events[1]
+            .1
+            .iter()
+            .map(|(label, count)| (label.as_str(), count.as_u64().unwrap()))
+            .collect();
+        assert_eq!(
+            annotations,
+            [
+                ("(..., 0)", 1),
+                ("[100, ...)", 2),
+                ("[1024, 2048)", 0),
+                ("[4, 8)", 3),
+                ("[8]", 1),
+                ("count", 7),
+            ]
+        );
+        assert_eq!(events[2].0, "@ms[bash]");
+        assert_eq!(events[2].1["[0, 10)"], 4);
+        assert_eq!(events[2].1["count"], 4);
+    }
+
+    #[test]
+    fn records_before_the_first_event_get_its_ts() {
+        let lines = [
+            json!({"type": "printf", "data": "starting\n"}),
+            json!({"type": "map", "data": {"@total": 12}}),
+            json!({"type": "hist", "data": {"@usecs": [{"min": 4, "max": 7, "count": 3}]}}),
+            instant("a", 5_000),
+            json!({"type": "printf", "data": "done\n"}),
+            instant("b", 6_000),
+        ];
+        let trace = convert(&lines, &[]);
+
+        let events: Vec<(String, u64)> = {
+            let names = track_events(&trace).into_iter().map(|(name, _)| name);
+            let timestamps = trace
+                .packet
+                .iter()
+                .filter(|p| p.has_track_event())
+                .map(|p| p.timestamp());
+            names.zip(timestamps).collect()
+        };
+        let expected = [
+            ("a", 5_000),
+            ("printf", 5_000),
+            ("@total", 5_000),
+            ("@usecs", 5_000),
+            ("printf", 5_000),
+            ("b", 6_000),
+        ]
+        .map(|(name, ts)| (name.to_string(), ts));
+        assert_eq!(events, expected);
+
+        // Without any events they're still added
+        let trace = convert(&lines[..2], &[]);
+        assert_eq!(counters(&trace), [("@total".to_string(), 0, 12)]);
+    }
+
+    #[test]
+    fn stats_leave_out_btetto_events() {
+        let lines = [
+            instant("a", 1),
+            json!({"type": "map", "data": {"@bytes": {"bash": 4096}}}),
+            json!({"type": "stats", "data": {"@s": {"count": 3, "average": 40, "total": 120}}}),
+            json!({"type": "hist", "data": {"@usecs": [{"min": 4, "max": 7, "count": 3}]}}),
+            json!({"type": "printf", "data": "opened /etc/passwd\n"}),
+            json!({"type": "lost_events", "data": {"events": 12}}),
+            instant("a", 5),
+        ];
+        let trace = convert(&lines, &["stats"]);
+
+        let names: Vec<String> = track_events(&trace)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, ["a", "a"]);
+        let track_names: Vec<&str> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor() && p.track_descriptor().thread.is_none())
+            .map(|p| p.track_descriptor().name())
+            .collect();
+        assert!(track_names.is_empty(), "{track_names:?}");
+    }
 }
@@ -0,0 +1,153 @@
+use std::collections::HashSet;
+use std::fs;
+
+use protobuf::Message;
+
+use crate::compress;
+use crate::protos::protos_gen::perfetto_bpftrace::{trace_packet, Trace};
+
+// An existing Perfetto trace for --merge-into and the ids already taken in it
+pub struct ExistingTrace {
+    pub trace: Trace,
+    pub max_sequence_id: u32,
+    pub track_uuids: HashSet<u64>,
+    pub flow_ids: HashSet<u64>,
+    // (pid, tid, track uuid) of the thread tracks
+    pub thread_tracks: Vec<(u64, u64, u64)>,
+    pub has_trace_uuid: bool,
+    pub has_system_info: bool,
+    pub has_trace_config: bool,
+}
+
+pub fn read_trace(filename: &str) -> ExistingTrace {
+    let Ok(bytes) = fs::read(filename) else {
+        panic!("Could not read trace file {filename}");
+    };
+    let Ok(trace) = Trace::parse_from_bytes(&bytes) else {
+        panic!("Could not parse Perfetto trace file {filename}");
+    };
+    let trace = compress::decompress_packets(trace);
+
+    let mut existing = ExistingTrace {
+        trace: Trace::new(),
+        max_sequence_id: 0,
+        track_uuids: HashSet::new(),
+        flow_ids: HashSet::new(),
+        thread_tracks: Vec::new(),
+        has_trace_uuid: false,
+        has_system_info: false,
+        has_trace_config: false,
+    };
+
+    for packet in &trace.packet {
+        existing.max_sequence_id = existing
+            .max_sequence_id
+            .max(packet.trusted_packet_sequence_id());
+        match &packet.data {
+            Some(trace_packet::Data::TrackDescriptor(track_descriptor)) => {
+                existing.track_uuids.insert(track_descriptor.uuid());
+                if let Some(thread) = track_descriptor.thread.as_ref() {
+                    existing.thread_tracks.push((
+                        thread.pid() as u64,
+                        thread.tid() as u64,
+                        track_descriptor.uuid(),
+                    ));
+                }
+            }
+            Some(trace_packet::Data::TrackEvent(track_event)) => {
+                existing.flow_ids.extend(&track_event.flow_ids);
+            }
+            Some(trace_packet::Data::TraceUuid(_)) => existing.has_trace_uuid = true,
+            Some(trace_packet::Data::SystemInfo(_)) => existing.has_system_info = true,
+            Some(trace_packet::Data::TraceConfig(_)) => existing.has_trace_config = true,
+            _ => {}
+        }
+    }
+
+    existing.trace = trace;
+    existing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::{env, process};
+
+    use serde_json::{json, Value};
+
+    use crate::tests::{convert, track_events};
+
+    fn event(name: &str, ts: u64, tid: u64) -> Value {
+        json!({"type": "value", "data": ["track_event", ["name", name], ["type", "INSTANT"],
+            ["ts", ts], ["pid", 1], ["tid", tid], ["thread_name", format!("worker{tid}")]]})
+    }
+
+    fn queue_event(name: &str, ts: u64) -> Value {
+        json!({"type": "value", "data": ["track_event", ["name", name], ["type", "INSTANT"],
+            ["ts", ts], ["track", "queue"]]})
+    }
+
+    #[test]
+    fn merge_into_existing_trace() {
+        let existing_file =
+            env::temp_dir().join(format!("btetto-test-{}-merge.pftrace", process::id()));
+        let existing_file = existing_file.to_str().unwrap();
+        let existing = convert(&[event("a", 1_000, 2), queue_event("q", 1_500)], &[]);
+        fs::write(existing_file, existing.write_to_bytes().unwrap()).unwrap();
+        let existing = read_trace(existing_file);
+
+        let merged = convert(
+            &[
+                event("b", 2_000, 2),
+                event("c", 3_000, 3),
+                queue_event("r", 3_500),
+            ],
+            &["--merge-into", existing_file],
+        );
+        fs::remove_file(existing_file).unwrap();
+
+        let names: Vec<String> = track_events(&merged)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, ["a", "q", "b", "c", "r"]);
+
+        // Track uuids are unique across both traces
+        let mut track_uuids = HashSet::new();
+        let mut thread_tracks: HashMap<i32, Vec<u64>> = HashMap::new();
+        for packet in merged.packet.iter().filter(|p| p.has_track_descriptor()) {
+            let track_descriptor = packet.track_descriptor();
+            assert!(track_uuids.insert(track_descriptor.uuid()));
+            if let Some(thread) = track_descriptor.thread.as_ref() {
+                thread_tracks
+                    .entry(thread.tid())
+                    .or_default()
+                    .push(track_descriptor.uuid());
+            }
+        }
+
+        // The existing thread track is reused, tid 3's is new
+        assert_eq!(thread_tracks[&2].len(), 1);
+        assert_eq!(thread_tracks[&3].len(), 1);
+        let event_tracks: Vec<u64> = merged
+            .packet
+            .iter()
+            .filter(|p| p.has_track_event())
+            .map(|p| p.track_event().track_uuid())
+            .collect();
+        assert_eq!(event_tracks[0], thread_tracks[&2][0]);
+        assert_eq!(event_tracks[2], thread_tracks[&2][0]);
+        assert_eq!(event_tracks[3], thread_tracks[&3][0]);
+        // Named tracks are always new
+        assert_ne!(event_tracks[4], event_tracks[1]);
+
+        // The new packets are on sequences of their own
+        let new_packets = &merged.packet[existing.trace.packet.len()..];
+        assert!(new_packets
+            .iter()
+            .filter(|p| p.trusted_packet_sequence_id() != 0)
+            .all(|p| p.trusted_packet_sequence_id() > existing.max_sequence_id));
+    }
+}
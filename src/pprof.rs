@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use protobuf::Message;
+
+use crate::interned::{self, Interned, SequenceState};
+use crate::protos::protos_gen::perfetto_bpftrace::{trace_packet, Trace};
+use crate::protos::protos_gen::profile::{
+    Function, Label, Line, Location, Mapping, Profile, Sample, ValueType,
+};
+
+struct ProfileBuilder {
+    profile: Profile,
+    string_ids: HashMap<String, i64>,
+    function_ids: HashMap<(String, String), u64>,
+    mapping_ids: HashMap<String, u64>,
+    // Keyed by (function name, mapping path, rel_pc) since frame iids are
+    // only unique within a sequence
+    location_ids: HashMap<(String, String, u64), u64>,
+    // Keyed by (location ids, pid, tid)
+    sample_indexes: HashMap<(Vec<u64>, u32, u32), usize>,
+    thread_names: HashMap<(u32, u32), String>,
+}
+
+struct PendingSample {
+    sequence_id: u32,
+    // Bottom frame first
+    frame_ids: Vec<u64>,
+    pid: u32,
+    tid: u32,
+}
+
+// Writes the call stack samples in the trace as a gzip compressed pprof
+// profile (https://github.com/google/pprof/blob/main/proto/profile.proto).
+// Returns the number of samples written.
+pub fn write_pprof(trace: &Trace, filename: &str) -> usize {
+    let mut builder = ProfileBuilder {
+        profile: Profile::new(),
+        string_ids: HashMap::new(),
+        function_ids: HashMap::new(),
+        mapping_ids: HashMap::new(),
+        location_ids: HashMap::new(),
+        sample_indexes: HashMap::new(),
+        thread_names: HashMap::new(),
+    };
+    // string_table[0] must always be ""
+    builder.get_string_id("");
+
+    let mut sample_type = ValueType::new();
+    sample_type.type_ = builder.get_string_id("samples");
+    sample_type.unit = builder.get_string_id("count");
+    builder.profile.sample_type.push(sample_type);
+
+    let mut sequence_state = SequenceState::default();
+    // Symbols for a frame are emitted after the sample that introduced it so
+    // samples are only resolved once their sequence's interned data is final
+    let mut pending: Vec<PendingSample> = Vec::new();
+    // (min, max) of the sample timestamps, which aren't sorted when the
+    // trace has several inputs
+    let mut time_range: Option<(u64, u64)> = None;
+    let mut num_samples = 0;
+
+    for packet in &trace.packet {
+        if interned::is_incremental_state_cleared(packet) {
+            let sequence_id = packet.trusted_packet_sequence_id();
+            builder.flush_samples(&mut pending, sequence_id, &sequence_state);
+        }
+
+        let interned = sequence_state.update(packet);
+        match &packet.data {
+            Some(trace_packet::Data::TrackDescriptor(track_descriptor)) => {
+                if let Some(thread) = track_descriptor.thread.as_ref() {
+                    builder.thread_names.insert(
+                        (thread.pid() as u32, thread.tid() as u32),
+                        thread.thread_name().to_string(),
+                    );
+                }
+            }
+            Some(trace_packet::Data::PerfSample(perf_sample)) => {
+                let Some(frame_ids) = interned.callstacks.get(&perf_sample.callstack_iid()) else {
+                    continue;
+                };
+                pending.push(PendingSample {
+                    sequence_id: packet.trusted_packet_sequence_id(),
+                    frame_ids: frame_ids.clone(),
+                    pid: perf_sample.pid(),
+                    tid: perf_sample.tid(),
+                });
+
+                let ts = packet.timestamp();
+                time_range =
+                    Some(time_range.map_or((ts, ts), |(min, max)| (min.min(ts), max.max(ts))));
+                num_samples += 1;
+            }
+            _ => {}
+        }
+    }
+
+    let sequence_ids: Vec<u32> = pending.iter().map(|p| p.sequence_id).collect();
+    for sequence_id in sequence_ids {
+        builder.flush_samples(&mut pending, sequence_id, &sequence_state);
+    }
+
+    if let Some((first_ts, last_ts)) = time_range {
+        builder.profile.duration_nanos = (last_ts - first_ts) as i64;
+    }
+
+    let out_bytes: Vec<u8> = builder.profile.write_to_bytes().unwrap();
+    let file = File::create(filename).expect("Could not create pprof file");
+    let mut encoder = GzEncoder::new(file, Compression::default());
+    encoder
+        .write_all(&out_bytes)
+        .and_then(|_| encoder.finish().map(|_| ()))
+        .expect("Could not write pprof file");
+
+    num_samples
+}
+
+impl ProfileBuilder {
+    fn flush_samples(
+        &mut self,
+        pending: &mut Vec<PendingSample>,
+        sequence_id: u32,
+        sequence_state: &SequenceState,
+    ) {
+        let Some(interned) = sequence_state.get(sequence_id) else {
+            return;
+        };
+        let (samples, rest): (Vec<PendingSample>, Vec<PendingSample>) = pending
+            .drain(..)
+            .partition(|p| p.sequence_id == sequence_id);
+        *pending = rest;
+        for sample in samples {
+            // pprof wants the leaf first
+            let location_ids: Vec<u64> = sample
+                .frame_ids
+                .iter()
+                .rev()
+                .map(|frame_iid| self.get_location_id(*frame_iid, interned))
+                .collect();
+            self.add_sample(location_ids, sample.pid, sample.tid);
+        }
+    }
+
+    fn get_string_id(&mut self, s: &str) -> i64 {
+        if let Some(id) = self.string_ids.get(s) {
+            return *id;
+        }
+        let id = self.profile.string_table.len() as i64;
+        self.profile.string_table.push(s.to_string());
+        self.string_ids.insert(s.to_string(), id);
+        id
+    }
+
+    fn get_function_id(&mut self, name: &str, filename: &str) -> u64 {
+        let key = (name.to_string(), filename.to_string());
+        if let Some(id) = self.function_ids.get(&key) {
+            return *id;
+        }
+        let mut function = Function::new();
+        function.id = self.profile.function.len() as u64 + 1;
+        function.name = self.get_string_id(name);
+        function.system_name = function.name;
+        function.filename = self.get_string_id(filename);
+        self.function_ids.insert(key, function.id);
+        self.profile.function.push(function.clone());
+        function.id
+    }
+
+    fn get_mapping_id(&mut self, path: &str) -> u64 {
+        if let Some(id) = self.mapping_ids.get(path) {
+            return *id;
+        }
+        let mut mapping = Mapping::new();
+        mapping.id = self.profile.mapping.len() as u64 + 1;
+        mapping.filename = self.get_string_id(path);
+        mapping.has_functions = true;
+        self.mapping_ids.insert(path.to_string(), mapping.id);
+        self.profile.mapping.push(mapping.clone());
+        mapping.id
+    }
+
+    fn get_location_id(&mut self, frame_iid: u64, interned: &Interned) -> u64 {
+        let name = interned.get_frame_name(frame_iid);
+        let frame = interned.frames.get(&frame_iid);
+        let rel_pc = frame.map_or(0, |f| f.rel_pc());
+        let mapping_path = frame.and_then(|f| interned.get_mapping_path(f.mapping_id()));
+
+        let key = (
+            name.clone(),
+            mapping_path.clone().unwrap_or_default(),
+            rel_pc,
+        );
+        if let Some(id) = self.location_ids.get(&key) {
+            return *id;
+        }
+
+        let mut location = Location::new();
+        location.id = self.profile.location.len() as u64 + 1;
+        location.address = rel_pc;
+        if let Some(mapping_path) = &mapping_path {
+            location.mapping_id = self.get_mapping_id(mapping_path);
+        }
+
+        // Offline symbolization adds inlined frames with file and line info,
+        // innermost first which is also the order pprof expects
+        match interned.frame_symbols.get(&frame_iid) {
+            Some(frame_symbols) => {
+                if location.mapping_id != 0 {
+                    let mapping = &mut self.profile.mapping[location.mapping_id as usize - 1];
+                    mapping.has_filenames = true;
+                    mapping.has_line_numbers = true;
+                    mapping.has_inline_frames = true;
+                }
+                for i in 0..frame_symbols.function_name_id.len() {
+                    let function_name = interned
+                        .function_names
+                        .get(&frame_symbols.function_name_id[i])
+                        .cloned()
+                        .unwrap_or_else(|| name.clone());
+                    let filename = frame_symbols
+                        .file_name_id
+                        .get(i)
+                        .and_then(|id| interned.source_paths.get(id))
+                        .cloned()
+                        .unwrap_or_default();
+                    let mut line = Line::new();
+                    line.function_id = self.get_function_id(&function_name, &filename);
+                    line.line = frame_symbols.line_number.get(i).copied().unwrap_or(0) as i64;
+                    location.line.push(line);
+                }
+            }
+            None => {
+                let mut line = Line::new();
+                line.function_id = self.get_function_id(&name, "");
+                location.line.push(line);
+            }
+        }
+
+        self.location_ids.insert(key, location.id);
+        self.profile.location.push(location.clone());
+        location.id
+    }
+
+    fn add_sample(&mut self, location_ids: Vec<u64>, pid: u32, tid: u32) {
+        let key = (location_ids, pid, tid);
+        if let Some(index) = self.sample_indexes.get(&key) {
+            self.profile.sample[*index].value[0] += 1;
+            return;
+        }
+
+        let mut sample = Sample::new();
+        sample.location_id = key.0.clone();
+        sample.value.push(1);
+
+        let mut pid_label = Label::new();
+        pid_label.key = self.get_string_id("pid");
+        pid_label.num = pid as i64;
+        sample.label.push(pid_label);
+
+        let mut tid_label = Label::new();
+        tid_label.key = self.get_string_id("tid");
+        tid_label.num = tid as i64;
+        sample.label.push(tid_label);
+
+        if let Some(thread_name) = self.thread_names.get(&(pid, tid)).cloned() {
+            let mut thread_label = Label::new();
+            thread_label.key = self.get_string_id("thread_name");
+            thread_label.str = self.get_string_id(&thread_name);
+            sample.label.push(thread_label);
+        }
+
+        self.sample_indexes.insert(key, self.profile.sample.len());
+        self.profile.sample.push(sample);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+    use std::{env, fs, process};
+
+    use crate::tests::{call_stack, convert};
+
+    #[test]
+    fn pprof_export() {
+        // Not in ts order, like a trace of several inputs
+        let lines = [
+            call_stack(3_000, 11, "\n f0+1\n main+4\n"),
+            call_stack(1_000, 11, "\n f0+1\n main+4\n"),
+            call_stack(2_000, 12, "\n f1+1\n main+4\n"),
+        ];
+        let trace = convert(&lines, &[]);
+        let output_file = env::temp_dir().join(format!("btetto-test-{}.pb.gz", process::id()));
+        let output_file = output_file.to_str().unwrap();
+        assert_eq!(write_pprof(&trace, output_file), 3);
+
+        let mut bytes = Vec::new();
+        flate2::read::GzDecoder::new(fs::File::open(output_file).unwrap())
+            .read_to_end(&mut bytes)
+            .unwrap();
+        fs::remove_file(output_file).unwrap();
+        let profile = Profile::parse_from_bytes(&bytes).unwrap();
+
+        assert_eq!(profile.duration_nanos, 2_000);
+        let function_names: HashMap<u64, &str> = profile
+            .function
+            .iter()
+            .map(|f| (f.id, profile.string_table[f.name as usize].as_str()))
+            .collect();
+        let location_names: HashMap<u64, &str> = profile
+            .location
+            .iter()
+            .map(|l| (l.id, function_names[&l.line[0].function_id]))
+            .collect();
+        let mut samples: Vec<(Vec<&str>, i64)> = profile
+            .sample
+            .iter()
+            .map(|sample| {
+                let stack = sample.location_id.iter().map(|id| location_names[id]);
+                (stack.collect(), sample.value[0])
+            })
+            .collect();
+        samples.sort();
+        // Leaf first
+        assert_eq!(
+            samples,
+            [(vec!["f0+1", "main+4"], 2), (vec!["f1+1", "main+4"], 1)]
+        );
+    }
+}
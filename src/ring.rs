@@ -0,0 +1,356 @@
+use std::collections::{HashMap, HashSet};
+
+use protobuf::Message;
+
+use crate::args::RingBufferLimit;
+use crate::protos::protos_gen::perfetto_bpftrace::{
+    trace_packet, InternedData, Trace, TracePacket,
+};
+
+// Flight recorder mode: only the events in the last span of time or size of
+// packets are kept. Everything else (track descriptors, clock snapshots, frame
+// symbols...) is put aside, and the interned data of the dropped events is
+// carried over so the events that are left can still be decoded.
+pub struct RingBuffer {
+    limit: RingBufferLimit,
+    // Serialized size of the packets in the trace, up to `counted`
+    size: u64,
+    counted: usize,
+    kept: Vec<TracePacket>,
+    // Interned data of the dropped events, keyed by sequence id
+    carried: HashMap<u32, InternedData>,
+}
+
+impl RingBuffer {
+    pub fn new(limit: RingBufferLimit) -> RingBuffer {
+        RingBuffer {
+            limit,
+            size: 0,
+            counted: 0,
+            kept: Vec::new(),
+            carried: HashMap::new(),
+        }
+    }
+
+    // Drops the events that fell out of the window. Trimming only starts a
+    // quarter past the limit so the packets aren't shifted on every record.
+    pub fn trim(&mut self, trace: &mut Trace, last_ts: u64) {
+        self.trim_to(trace, last_ts, true);
+    }
+
+    fn trim_to(&mut self, trace: &mut Trace, last_ts: u64, with_slack: bool) {
+        for packet in &trace.packet[self.counted..] {
+            self.size += packet.compute_size();
+        }
+        self.counted = trace.packet.len();
+
+        let num_dropped = match self.limit {
+            RingBufferLimit::Size(limit) => {
+                let slack = if with_slack { limit / 4 } else { 0 };
+                if self.size <= limit + slack {
+                    return;
+                }
+                let mut size = self.size;
+                let mut num_dropped = 0;
+                for packet in &trace.packet {
+                    if size <= limit {
+                        break;
+                    }
+                    size -= packet.compute_size();
+                    num_dropped += 1;
+                }
+                num_dropped
+            }
+            RingBufferLimit::Span(span) => {
+                let slack = if with_slack { span / 4 } else { 0 };
+                let first_ts = trace
+                    .packet
+                    .iter()
+                    .find(|p| is_event(p))
+                    .map(|p| p.timestamp());
+                if first_ts.is_none_or(|ts| ts + span + slack >= last_ts) {
+                    return;
+                }
+                trace
+                    .packet
+                    .iter()
+                    .take_while(|p| !is_event(p) || p.timestamp() + span < last_ts)
+                    .count()
+            }
+        };
+
+        for packet in trace.packet.drain(..num_dropped) {
+            self.size -= packet.compute_size();
+            if !is_event(&packet) {
+                self.kept.push(packet);
+                continue;
+            }
+            carry_interned_data(&mut self.carried, &packet);
+        }
+        self.counted -= num_dropped;
+    }
+
+    // Replaces the trace's packets with the ones in the window, see
+    // build_window(). Returns the timestamp of the first event in the window.
+    pub fn window(&mut self, trace: &mut Trace, last_ts: u64) -> Option<u64> {
+        self.trim_to(trace, last_ts, false);
+
+        let mut packets = std::mem::take(&mut self.kept);
+        packets.append(&mut trace.packet);
+        self.size = 0;
+        self.counted = 0;
+
+        let carried = std::mem::take(&mut self.carried);
+        let (packets, first_ts) = build_window(packets, carried, 0, u64::MAX);
+        trace.packet = packets;
+        first_ts
+    }
+
+    // A copy of the events between start_ts and end_ts, leaving the buffer as is
+    pub fn snapshot(&self, trace: &Trace, start_ts: u64, end_ts: u64) -> Trace {
+        let mut packets = self.kept.clone();
+        packets.extend(trace.packet.iter().cloned());
+
+        let mut snapshot = Trace::new();
+        snapshot.packet = build_window(packets, self.carried.clone(), start_ts, end_ts).0;
+        snapshot
+    }
+}
+
+// A copy of the events in a trace that isn't a ring buffer between start_ts
+// and end_ts
+pub fn snapshot(trace: &Trace, start_ts: u64, end_ts: u64) -> Trace {
+    let mut snapshot = Trace::new();
+    snapshot.packet = build_window(trace.packet.clone(), HashMap::new(), start_ts, end_ts).0;
+    snapshot
+}
+
+// Drops the events outside of start_ts..=end_ts, and the track descriptors
+// of tracks with no events left. The interned data of every dropped packet is
+// carried to the next packet kept on its sequence: a sequence with nothing
+// kept before it begins with a packet holding the data carried over, later
+// on it's merged into the kept packet.
+fn build_window(
+    packets: Vec<TracePacket>,
+    mut carried: HashMap<u32, InternedData>,
+    start_ts: u64,
+    end_ts: u64,
+) -> (Vec<TracePacket>, Option<u64>) {
+    let is_in_window = |packet: &TracePacket| {
+        !is_event(packet) || (start_ts..=end_ts).contains(&packet.timestamp())
+    };
+
+    let mut track_uuids: HashSet<u64> = HashSet::new();
+    let mut threads: HashSet<(i32, i32)> = HashSet::new();
+    let mut parent_uuids: HashMap<u64, u64> = HashMap::new();
+    for packet in packets.iter().filter(|p| is_in_window(p)) {
+        match &packet.data {
+            Some(trace_packet::Data::TrackEvent(track_event)) => {
+                track_uuids.insert(track_event.track_uuid());
+            }
+            Some(trace_packet::Data::PerfSample(perf_sample)) => {
+                threads.insert((perf_sample.pid() as i32, perf_sample.tid() as i32));
+            }
+            Some(trace_packet::Data::TrackDescriptor(track_descriptor)) => {
+                if let Some(parent_uuid) = track_descriptor.parent_uuid {
+                    parent_uuids.insert(track_descriptor.uuid(), parent_uuid);
+                }
+            }
+            _ => {}
+        }
+    }
+    for uuid in track_uuids.clone() {
+        let mut uuid = uuid;
+        while let Some(parent_uuid) = parent_uuids.get(&uuid) {
+            track_uuids.insert(*parent_uuid);
+            uuid = *parent_uuid;
+        }
+    }
+    let is_track_used = |packet: &TracePacket| match &packet.data {
+        Some(trace_packet::Data::TrackDescriptor(track_descriptor)) => {
+            let is_thread_used = track_descriptor
+                .thread
+                .as_ref()
+                .is_some_and(|t| threads.contains(&(t.pid(), t.tid())));
+            is_thread_used || track_uuids.contains(&track_descriptor.uuid())
+        }
+        _ => true,
+    };
+
+    let first_ts = packets
+        .iter()
+        .filter(|p| is_event(p) && is_in_window(p))
+        .map(|p| p.timestamp())
+        .min();
+
+    let mut window = Vec::new();
+    // Sequences with a packet kept so far, and those of them that begin with
+    // a carried packet
+    let mut kept_sequence_ids = HashSet::new();
+    let mut carried_sequence_ids = HashSet::new();
+    for mut packet in packets {
+        if !is_in_window(&packet) || !is_track_used(&packet) {
+            carry_interned_data(&mut carried, &packet);
+            continue;
+        }
+
+        let sequence_id = packet.trusted_packet_sequence_id();
+        if let Some(interned_data) = carried.remove(&sequence_id) {
+            if kept_sequence_ids.contains(&sequence_id) {
+                prepend_interned_data(&mut packet, interned_data);
+            } else {
+                window.push(carried_packet(sequence_id, interned_data));
+                carried_sequence_ids.insert(sequence_id);
+            }
+        }
+        kept_sequence_ids.insert(sequence_id);
+        // The carried packet starts the sequence instead
+        if carried_sequence_ids.contains(&sequence_id) && packet.first_packet_on_sequence() {
+            packet.first_packet_on_sequence = None;
+            packet.sequence_flags =
+                Some(trace_packet::SequenceFlags::SEQ_NEEDS_INCREMENTAL_STATE as u32);
+        }
+        window.push(packet);
+    }
+
+    (window, first_ts)
+}
+
+// Interned data carried to a packet goes before its own, which may refer to it
+fn prepend_interned_data(packet: &mut TracePacket, mut interned_data: InternedData) {
+    if let Some(own) = packet.interned_data.as_ref() {
+        interned_data
+            .merge_from_bytes(&own.write_to_bytes().unwrap())
+            .unwrap();
+    }
+    packet.interned_data = Some(interned_data).into();
+}
+
+fn carry_interned_data(carried: &mut HashMap<u32, InternedData>, packet: &TracePacket) {
+    if let Some(interned_data) = packet.interned_data.as_ref() {
+        carried
+            .entry(packet.trusted_packet_sequence_id())
+            .or_default()
+            .merge_from_bytes(&interned_data.write_to_bytes().unwrap())
+            .unwrap();
+    }
+}
+
+fn is_event(packet: &TracePacket) -> bool {
+    matches!(
+        packet.data,
+        Some(trace_packet::Data::TrackEvent(_)) | Some(trace_packet::Data::PerfSample(_))
+    )
+}
+
+fn carried_packet(sequence_id: u32, interned_data: InternedData) -> TracePacket {
+    let mut packet = TracePacket::new();
+    packet.optional_trusted_packet_sequence_id = Some(
+        trace_packet::Optional_trusted_packet_sequence_id::TrustedPacketSequenceId(sequence_id),
+    );
+    packet.first_packet_on_sequence = Some(true);
+    packet.previous_packet_dropped = Some(true);
+    packet.sequence_flags = Some(
+        (trace_packet::SequenceFlags::SEQ_INCREMENTAL_STATE_CLEARED as u32)
+            | (trace_packet::SequenceFlags::SEQ_NEEDS_INCREMENTAL_STATE as u32),
+    );
+    packet.interned_data = Some(interned_data).into();
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use crate::interned::SequenceState;
+    use crate::tests::{call_stack, convert, instant, track_events};
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn event(name: &str, ts: u64, tid: u64) -> Value {
+        json!({"type": "value", "data": ["track_event", ["name", name], ["type", "INSTANT"],
+            ["ts", ts], ["pid", 1], ["tid", tid], ["thread_name", format!("worker{tid}")]]})
+    }
+
+    fn names(trace: &crate::Trace) -> Vec<String> {
+        track_events(trace)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    #[test]
+    fn trim_by_span() {
+        let lines: Vec<Value> = (0..10)
+            .map(|i| instant(&format!("e{i}"), (i + 1) * SECOND))
+            .collect();
+        let trace = convert(&lines, &["--ring-buffer", "3s"]);
+        assert_eq!(names(&trace), ["e6", "e7", "e8", "e9"]);
+    }
+
+    #[test]
+    fn trim_by_size() {
+        let lines: Vec<Value> = (0..200)
+            .map(|i| instant(&format!("e{i}"), (i + 1) * SECOND))
+            .collect();
+        let trace = convert(&lines, &["--ring-buffer", "1KB"]);
+        let names = names(&trace);
+        assert!(names.len() > 10 && names.len() < 200, "{}", names.len());
+        // The latest events are the ones kept
+        assert_eq!(names.last().unwrap(), "e199");
+        let first: usize = names[0][1..].parse().unwrap();
+        let expected: Vec<String> = (first..200).map(|i| format!("e{i}")).collect();
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn window_resolves_interned_data() {
+        // The names and the stack are interned by the first events, which
+        // are dropped
+        let mut lines = Vec::new();
+        for i in 0..10 {
+            lines.push(instant("a", (i + 1) * SECOND));
+            lines.push(call_stack((i + 1) * SECOND, 11, "\n f0+1\n main+4\n"));
+        }
+        let trace = convert(&lines, &["--ring-buffer", "2s"]);
+
+        assert_eq!(names(&trace), ["a", "a", "a"]);
+        let mut sequence_state = SequenceState::default();
+        let mut stacks = Vec::new();
+        for packet in &trace.packet {
+            let interned = sequence_state.update(packet);
+            if packet.has_perf_sample() {
+                stacks.push(interned.get_callstack_names(packet.perf_sample().callstack_iid()));
+            }
+        }
+        assert_eq!(stacks, vec![vec!["main+4", "f0+1"]; 3]);
+    }
+
+    #[test]
+    fn descriptors_of_tracks_in_the_window_are_kept() {
+        let lines = [
+            event("early", SECOND, 1),
+            event("late", 5 * SECOND, 2),
+            event("late", 6 * SECOND, 2),
+        ];
+        let trace = convert(&lines, &["--ring-buffer", "2s"]);
+
+        assert_eq!(names(&trace), ["late", "late"]);
+        let threads: Vec<(i32, &str)> = trace
+            .packet
+            .iter()
+            .filter(|p| p.has_track_descriptor())
+            .filter_map(|p| p.track_descriptor().thread.as_ref())
+            .map(|thread| (thread.tid(), thread.thread_name()))
+            .collect();
+        assert_eq!(threads, [(2, "worker2")]);
+        // Every event's track is described
+        for packet in trace.packet.iter().filter(|p| p.has_track_event()) {
+            let uuid = packet.track_event().track_uuid();
+            assert!(trace
+                .packet
+                .iter()
+                .any(|p| p.has_track_descriptor() && p.track_descriptor().uuid() == uuid));
+        }
+    }
+}
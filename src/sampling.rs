@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::util;
+
+// Drop counts are reported per second of trace time
+const BUCKET_NS: u64 = 1_000_000_000;
+
+// --sample (keep 1 in N) and --rate-limit (keep at most N per second), both
+// per event name. Call stack samples all count as one name, "call_stack".
+// Whether a slice is kept is decided on its BEGIN and its END follows it so
+// slices are never left half open.
+pub struct Sampler {
+    sample_every: Option<u64>,
+    rate_limit: Option<u64>,
+    names: HashMap<String, NameState>,
+    // Whether each open BEGIN was kept, keyed by track
+    open_slices: HashMap<String, Vec<bool>>,
+    pub total_dropped: u64,
+}
+
+#[derive(Default)]
+struct NameState {
+    num_seen: u64,
+    bucket_start: u64,
+    num_kept: u64,
+    num_dropped: u64,
+    last_reported: u64,
+}
+
+// (name, ts, events dropped in the second starting at ts)
+pub type DroppedCount = (String, u64, u64);
+
+impl Sampler {
+    pub fn new(sample_every: Option<u64>, rate_limit: Option<u64>) -> Sampler {
+        Sampler {
+            sample_every,
+            rate_limit,
+            names: HashMap::new(),
+            open_slices: HashMap::new(),
+            total_dropped: 0,
+        }
+    }
+
+    // data is a track_event or call_stack record with its ts converted.
+    // Returns whether to keep it and the drop counts of the seconds that
+    // ended before it.
+    pub fn sample(&mut self, data: &Value) -> (bool, Vec<DroppedCount>) {
+        let get = |key: &str| {
+            data.as_array()
+                .unwrap()
+                .iter()
+                .find(|pair| pair[0] == key)
+                .map(|pair| &pair[1])
+        };
+        let event_type = get("type").and_then(Value::as_str);
+        let track = util::slice_track(data);
+
+        if event_type == Some("END") {
+            let is_kept = self
+                .open_slices
+                .get_mut(&track)
+                .and_then(|open| open.pop())
+                .unwrap_or(true);
+            return (is_kept, Vec::new());
+        }
+
+        let name = if data[0] == "call_stack" {
+            "call_stack".to_string()
+        } else {
+            get("name").map(util::value_str).unwrap_or_default()
+        };
+        let ts = get("ts").and_then(Value::as_u64).unwrap_or(0);
+
+        let state = self.names.entry(name.clone()).or_default();
+        let mut dropped_counts = Vec::new();
+        if ts >= state.bucket_start + BUCKET_NS {
+            if state.num_dropped > 0 || state.last_reported > 0 {
+                dropped_counts.push((name.clone(), state.bucket_start, state.num_dropped));
+                state.last_reported = state.num_dropped;
+            }
+            state.bucket_start = ts;
+            state.num_kept = 0;
+            state.num_dropped = 0;
+        }
+
+        state.num_seen += 1;
+        let is_sampled = self
+            .sample_every
+            .is_none_or(|every| (state.num_seen - 1).is_multiple_of(every));
+        let is_kept = is_sampled && self.rate_limit.is_none_or(|limit| state.num_kept < limit);
+        if is_kept {
+            state.num_kept += 1;
+        } else {
+            state.num_dropped += 1;
+            self.total_dropped += 1;
+        }
+
+        if event_type == Some("BEGIN") {
+            self.open_slices.entry(track).or_default().push(is_kept);
+        }
+        (is_kept, dropped_counts)
+    }
+
+    // The drop counts of the last second of each name
+    pub fn finish(&mut self) -> Vec<DroppedCount> {
+        let mut dropped_counts: Vec<DroppedCount> = self
+            .names
+            .iter_mut()
+            .filter(|(_, state)| state.num_dropped > 0 || state.last_reported > 0)
+            .map(|(name, state)| {
+                state.last_reported = state.num_dropped;
+                (name.clone(), state.bucket_start, state.num_dropped)
+            })
+            .collect();
+        dropped_counts.sort();
+        dropped_counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    const MS: u64 = 1_000_000;
+
+    fn event(event_type: &str, name: &str, ts: u64) -> Value {
+        json!([
+            "track_event",
+            ["name", name],
+            ["type", event_type],
+            ["ts", ts],
+            ["pid", 1],
+            ["tid", 2]
+        ])
+    }
+
+    fn kept(sampler: &mut Sampler, events: &[Value]) -> Vec<bool> {
+        events.iter().map(|e| sampler.sample(e).0).collect()
+    }
+
+    #[test]
+    fn one_in_n() {
+        let mut sampler = Sampler::new(Some(3), None);
+        let events: Vec<Value> = (0..7).map(|i| event("INSTANT", "a", i)).collect();
+        assert_eq!(
+            kept(&mut sampler, &events),
+            [true, false, false, true, false, false, true]
+        );
+        // Counted per name
+        assert!(sampler.sample(&event("INSTANT", "b", 7)).0);
+        // Call stack samples all count as one name
+        let stack = json!(["call_stack", ["ts", 8], ["pid", 1], ["tid", 2]]);
+        assert_eq!(kept(&mut sampler, &[stack.clone(), stack]), [true, false]);
+        assert_eq!(sampler.total_dropped, 5);
+    }
+
+    #[test]
+    fn rate_limit_per_name_and_second() {
+        let mut sampler = Sampler::new(None, Some(2));
+        let events = [
+            event("INSTANT", "a", 0),
+            event("INSTANT", "a", 100 * MS),
+            event("INSTANT", "a", 200 * MS),
+            event("INSTANT", "b", 300 * MS),
+            event("INSTANT", "a", 999 * MS),
+            // The next second
+            event("INSTANT", "a", 1_000 * MS),
+        ];
+        assert_eq!(
+            kept(&mut sampler, &events),
+            [true, true, false, true, false, true]
+        );
+    }
+
+    #[test]
+    fn slices_are_dropped_whole() {
+        let mut sampler = Sampler::new(Some(2), None);
+        let events = [
+            event("BEGIN", "a", 0),
+            event("BEGIN", "a", 1),
+            event("END", "", 2),
+            event("END", "", 3),
+            event("BEGIN", "a", 4),
+            event("END", "", 5),
+        ];
+        assert_eq!(
+            kept(&mut sampler, &events),
+            [true, false, false, true, true, true]
+        );
+        // ENDs aren't counted as dropped
+        assert_eq!(sampler.total_dropped, 1);
+    }
+
+    #[test]
+    fn dropped_counts() {
+        let mut sampler = Sampler::new(None, Some(1));
+        let mut dropped_counts = Vec::new();
+        for ts in [
+            0,
+            100 * MS,
+            200 * MS,
+            1_500 * MS,
+            2_600 * MS,
+            3_700 * MS,
+            3_800 * MS,
+        ] {
+            dropped_counts.extend(sampler.sample(&event("INSTANT", "a", ts)).1);
+        }
+        assert_eq!(
+            dropped_counts,
+            [
+                ("a".to_string(), 0, 2),
+                // A zero after drops so the counter goes back down
+                ("a".to_string(), 1_500 * MS, 0),
+            ]
+        );
+        assert_eq!(sampler.finish(), [("a".to_string(), 3_700 * MS, 1)]);
+        assert_eq!(sampler.total_dropped, 3);
+    }
+
+    #[test]
+    fn dropped_counter_tracks() {
+        let lines: Vec<Value> = [0, 100, 200, 1_500, 1_600]
+            .iter()
+            .map(|ms| crate::tests::instant("a", ms * MS))
+            .collect();
+        let trace = crate::tests::convert(&lines, &["--rate-limit", "1"]);
+
+        let mut track_names = HashMap::new();
+        let mut counters = Vec::new();
+        for packet in &trace.packet {
+            if packet.has_track_descriptor() {
+                let track_descriptor = packet.track_descriptor();
+                track_names.insert(track_descriptor.uuid(), track_descriptor.name().to_string());
+            }
+            if packet.has_track_event() && packet.track_event().has_counter_value() {
+                let track_event = packet.track_event();
+                counters.push((
+                    track_names[&track_event.track_uuid()].clone(),
+                    packet.timestamp(),
+                    track_event.counter_value(),
+                ));
+            }
+        }
+        assert_eq!(
+            counters,
+            [
+                ("a dropped".to_string(), 0, 2),
+                ("a dropped".to_string(), 1_500 * MS, 1),
+            ]
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::interned::SequenceState;
+use crate::protos::protos_gen::perfetto_bpftrace::{trace_packet, track_event, Trace};
+
+// Only the most common entries of the longer tables are printed
+const MAX_ROWS: usize = 20;
+
+// `btetto stats`: summarizes the converted input instead of writing it.
+// record_counts are the input records by type, counted as they were parsed.
+pub fn print_stats(trace: &Trace, record_counts: &BTreeMap<String, u64>) {
+    let mut sequence_state = SequenceState::default();
+    let mut track_names: HashMap<u64, String> = HashMap::new();
+    let mut name_counts: HashMap<String, u64> = HashMap::new();
+    let mut track_counts: HashMap<u64, u64> = HashMap::new();
+    let mut thread_counts: HashMap<(i32, i32), u64> = HashMap::new();
+    let mut track_threads: HashMap<u64, (i32, i32)> = HashMap::new();
+    // (ts, track uuid, type) of the slice events, matched after sorting
+    let mut slice_events: Vec<(u64, u64, track_event::Type)> = Vec::new();
+    let mut stack_depths: BTreeMap<u32, u64> = BTreeMap::new();
+    let mut time_range: Option<(u64, u64)> = None;
+
+    for packet in &trace.packet {
+        let interned = sequence_state.update(packet);
+        match &packet.data {
+            Some(trace_packet::Data::TrackDescriptor(track_descriptor)) => {
+                let name = match track_descriptor.thread.as_ref() {
+                    Some(thread) => {
+                        format!("{} {}/{}", thread.thread_name(), thread.pid(), thread.tid())
+                    }
+                    None => track_descriptor.name().to_string(),
+                };
+                track_names.insert(track_descriptor.uuid(), name);
+                if let Some(thread) = track_descriptor.thread.as_ref() {
+                    track_threads.insert(track_descriptor.uuid(), (thread.pid(), thread.tid()));
+                }
+            }
+            Some(trace_packet::Data::TrackEvent(track_event)) => {
+                *name_counts
+                    .entry(interned.get_event_name(track_event))
+                    .or_default() += 1;
+                *track_counts.entry(track_event.track_uuid()).or_default() += 1;
+                let type_ = track_event.type_();
+                if matches!(
+                    type_,
+                    track_event::Type::TYPE_SLICE_BEGIN | track_event::Type::TYPE_SLICE_END
+                ) {
+                    slice_events.push((packet.timestamp(), track_event.track_uuid(), type_));
+                }
+                update_time_range(&mut time_range, packet.timestamp());
+            }
+            Some(trace_packet::Data::PerfSample(perf_sample)) => {
+                *thread_counts
+                    .entry((perf_sample.pid() as i32, perf_sample.tid() as i32))
+                    .or_default() += 1;
+                let depth = interned
+                    .get_callstack_names(perf_sample.callstack_iid())
+                    .len() as u32;
+                *stack_depths.entry(depth_bucket(depth)).or_default() += 1;
+                update_time_range(&mut time_range, packet.timestamp());
+            }
+            _ => {}
+        }
+    }
+
+    // Thread track events count towards their thread too
+    for (uuid, count) in &track_counts {
+        if let Some(thread) = track_threads.get(uuid) {
+            *thread_counts.entry(*thread).or_default() += count;
+        }
+    }
+
+    println!("Records by type:");
+    print_rows(record_counts.iter().map(|(k, v)| (k.clone(), *v)).collect());
+
+    println!("\nTime range:");
+    match time_range {
+        Some((first, last)) => println!(
+            "  {first} - {last} ({:.3}s)",
+            (last - first) as f64 / 1_000_000_000.0
+        ),
+        None => println!("  no events"),
+    }
+
+    println!("\nEvents by name:");
+    print_rows(name_counts.into_iter().collect());
+
+    println!("\nEvents by track:");
+    print_rows(
+        track_counts
+            .into_iter()
+            .map(|(uuid, count)| {
+                let name = track_names
+                    .get(&uuid)
+                    .cloned()
+                    .unwrap_or_else(|| format!("uuid {uuid}"));
+                (name, count)
+            })
+            .collect(),
+    );
+
+    println!("\nEvents and samples by pid/tid:");
+    print_rows(
+        thread_counts
+            .into_iter()
+            .map(|((pid, tid), count)| (format!("{pid}/{tid}"), count))
+            .collect(),
+    );
+
+    let (unmatched_begins, unmatched_ends) = count_unmatched_slices(slice_events);
+    println!("\nUnmatched slices:");
+    println!("  BEGIN without END: {unmatched_begins}");
+    println!("  END without BEGIN: {unmatched_ends}");
+
+    println!("\nStack depth:");
+    if stack_depths.is_empty() {
+        println!("  no call stack samples");
+    }
+    for (bucket, count) in &stack_depths {
+        let range = if *bucket == 0 {
+            "[0]".to_string()
+        } else {
+            format!("[{}, {})", bucket, bucket * 2)
+        };
+        println!("  {range:<12} {count}");
+    }
+}
+
+fn update_time_range(time_range: &mut Option<(u64, u64)>, ts: u64) {
+    *time_range = Some(match *time_range {
+        Some((first, last)) => (first.min(ts), last.max(ts)),
+        None => (ts, ts),
+    });
+}
+
+// Power of 2 buckets like bpftrace's hist()
+fn depth_bucket(depth: u32) -> u32 {
+    if depth == 0 {
+        0
+    } else {
+        1 << (31 - depth.leading_zeros())
+    }
+}
+
+fn count_unmatched_slices(mut slice_events: Vec<(u64, u64, track_event::Type)>) -> (u64, u64) {
+    slice_events.sort_by_key(|(ts, _, _)| *ts);
+    let mut open_slices: HashMap<u64, u64> = HashMap::new();
+    let mut unmatched_ends = 0;
+    for (_, track_uuid, type_) in slice_events {
+        let open = open_slices.entry(track_uuid).or_default();
+        if type_ == track_event::Type::TYPE_SLICE_BEGIN {
+            *open += 1;
+        } else if *open > 0 {
+            *open -= 1;
+        } else {
+            unmatched_ends += 1;
+        }
+    }
+    (open_slices.values().sum(), unmatched_ends)
+}
+
+// Most common first
+fn print_rows(mut rows: Vec<(String, u64)>) {
+    if rows.is_empty() {
+        println!("  none");
+        return;
+    }
+    rows.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let width = rows
+        .iter()
+        .take(MAX_ROWS)
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0);
+    for (name, count) in rows.iter().take(MAX_ROWS) {
+        println!("  {name:<width$} {count}");
+    }
+    if rows.len() > MAX_ROWS {
+        println!("  ... {} more", rows.len() - MAX_ROWS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use track_event::Type::{TYPE_SLICE_BEGIN, TYPE_SLICE_END};
+
+    #[test]
+    fn depth_buckets_are_powers_of_2() {
+        assert_eq!(depth_bucket(0), 0);
+        assert_eq!(depth_bucket(1), 1);
+        assert_eq!(depth_bucket(2), 2);
+        assert_eq!(depth_bucket(3), 2);
+        assert_eq!(depth_bucket(4), 4);
+        assert_eq!(depth_bucket(127), 64);
+        assert_eq!(depth_bucket(128), 128);
+    }
+
+    #[test]
+    fn matched_slices_are_not_counted() {
+        let slice_events = vec![
+            (1, 1, TYPE_SLICE_BEGIN),
+            (2, 1, TYPE_SLICE_BEGIN),
+            (3, 1, TYPE_SLICE_END),
+            (4, 1, TYPE_SLICE_END),
+        ];
+        assert_eq!(count_unmatched_slices(slice_events), (0, 0));
+    }
+
+    #[test]
+    fn unmatched_slices_are_counted_per_track() {
+        let slice_events = vec![
+            (1, 1, TYPE_SLICE_BEGIN),
+            (2, 2, TYPE_SLICE_END),
+            (3, 2, TYPE_SLICE_BEGIN),
+        ];
+        assert_eq!(count_unmatched_slices(slice_events), (2, 1));
+    }
+
+    #[test]
+    fn slices_are_matched_in_ts_order() {
+        // The END is read first but happens after the BEGIN
+        let slice_events = vec![(2, 1, TYPE_SLICE_END), (1, 1, TYPE_SLICE_BEGIN)];
+        assert_eq!(count_unmatched_slices(slice_events), (0, 0));
+    }
+}
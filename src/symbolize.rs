@@ -0,0 +1,330 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use object::{Object, ObjectKind, ObjectSegment};
+
+pub struct SymbolLine {
+    pub function_name: String,
+    pub source_file: Option<String>,
+    pub line_number: Option<u32>,
+}
+
+pub struct Symbol {
+    pub name: String,
+    pub mapping_path: String,
+    // Offset of the address in the binary file. The mappings have no start
+    // address so this is relative to the start of the file.
+    pub rel_pc: u64,
+    // Innermost (inlined) function first
+    pub lines: Vec<SymbolLine>,
+}
+
+pub struct FrameAddress {
+    pub address: u64,
+    pub module: Option<String>,
+    pub module_offset: Option<u64>,
+}
+
+struct Binary {
+    path: String,
+    loader: addr2line::Loader,
+    // (file offset, file size, virtual address, memory size) for each
+    // loadable segment
+    segments: Vec<(u64, u64, u64, u64)>,
+    is_executable: bool,
+}
+
+pub struct Symbolizer {
+    symbol_dirs: Vec<PathBuf>,
+    binaries: HashMap<String, Option<Binary>>,
+    dir_binaries: Option<Vec<String>>,
+}
+
+impl Symbolizer {
+    pub fn new(symbol_dirs: &[String]) -> Symbolizer {
+        Symbolizer {
+            symbol_dirs: symbol_dirs.iter().map(PathBuf::from).collect(),
+            binaries: HashMap::new(),
+            dir_binaries: None,
+        }
+    }
+
+    // Frames that bpftrace already symbolized (e.g. "main+24") are left
+    // alone and return None. Raw addresses are resolved against the binary
+    // named in the frame or, for bare addresses, any binary in the symbol dirs.
+    pub fn symbolize(&mut self, frame: &str) -> Option<Symbol> {
+        let frame_address = parse_frame_address(frame)?;
+
+        if let Some(module) = &frame_address.module {
+            self.load_binary(module);
+            let binary = self.binaries[module].as_ref()?;
+            let vaddr = match frame_address.module_offset {
+                Some(offset) => binary.offset_to_vaddr(offset)?,
+                None if binary.is_executable => frame_address.address,
+                None => return None,
+            };
+            return binary.resolve(vaddr);
+        }
+
+        for name in self.get_dir_binaries() {
+            let binary = self.binaries[&name].as_ref().unwrap();
+            // Without the process mappings only non-PIE executables can be
+            // matched against an absolute address
+            if !binary.is_executable {
+                continue;
+            }
+            if let Some(symbol) = binary.resolve(frame_address.address) {
+                return Some(symbol);
+            }
+        }
+
+        None
+    }
+
+    fn load_binary(&mut self, module: &str) {
+        if self.binaries.contains_key(module) {
+            return;
+        }
+
+        let mut candidates: Vec<PathBuf> = Vec::new();
+        for dir in &self.symbol_dirs {
+            candidates.push(dir.join(module.trim_start_matches('/')));
+            if let Some(file_name) = Path::new(module).file_name() {
+                candidates.push(dir.join(file_name));
+            }
+        }
+        candidates.push(PathBuf::from(module));
+
+        let binary = candidates
+            .into_iter()
+            .filter(|path| path.is_file())
+            .find_map(|path| Binary::load(&path, module));
+        self.binaries.insert(module.to_string(), binary);
+    }
+
+    fn get_dir_binaries(&mut self) -> Vec<String> {
+        if self.dir_binaries.is_none() {
+            let mut names = Vec::new();
+            for dir in self.symbol_dirs.clone() {
+                let Ok(entries) = fs::read_dir(&dir) else {
+                    panic!("Could not read symbol dir {}", dir.display());
+                };
+                for entry in entries.map_while(Result::ok) {
+                    let path = entry.path();
+                    if !path.is_file() {
+                        continue;
+                    }
+                    let name = path.to_string_lossy().to_string();
+                    if let Some(binary) = Binary::load(&path, &name) {
+                        self.binaries.insert(name.clone(), Some(binary));
+                        names.push(name);
+                    }
+                }
+            }
+            self.dir_binaries = Some(names);
+        }
+
+        self.dir_binaries.clone().unwrap()
+    }
+}
+
+impl Binary {
+    fn load(path: &Path, mapping_path: &str) -> Option<Binary> {
+        let data = fs::read(path).ok()?;
+        let file = object::File::parse(&*data).ok()?;
+        let segments = file
+            .segments()
+            .map(|s| {
+                let (offset, size) = s.file_range();
+                (offset, size, s.address(), s.size())
+            })
+            .collect();
+        let is_executable = file.kind() == ObjectKind::Executable;
+        let loader = addr2line::Loader::new(path).ok()?;
+
+        Some(Binary {
+            path: mapping_path.to_string(),
+            loader,
+            segments,
+            is_executable,
+        })
+    }
+
+    fn offset_to_vaddr(&self, offset: u64) -> Option<u64> {
+        self.segments
+            .iter()
+            .find(|(start, size, _, _)| offset >= *start && offset < start + size)
+            .map(|(start, _, vaddr, _)| offset - start + vaddr)
+    }
+
+    fn resolve(&self, vaddr: u64) -> Option<Symbol> {
+        // The symbol table lookup returns the closest preceding symbol so
+        // addresses outside of the binary have to be rejected first
+        let (file_offset, _, segment_vaddr, _) = self
+            .segments
+            .iter()
+            .find(|(_, _, start, size)| vaddr >= *start && vaddr < start + size)?;
+
+        let mut lines = Vec::new();
+        if let Ok(mut frames) = self.loader.find_frames(vaddr) {
+            while let Ok(Some(frame)) = frames.next() {
+                let Some(function_name) = frame
+                    .function
+                    .as_ref()
+                    .and_then(|f| f.demangle().ok())
+                    .map(|name| name.to_string())
+                else {
+                    continue;
+                };
+                lines.push(SymbolLine {
+                    function_name,
+                    source_file: frame
+                        .location
+                        .as_ref()
+                        .and_then(|l| l.file)
+                        .map(|f| f.to_string()),
+                    line_number: frame.location.as_ref().and_then(|l| l.line),
+                });
+            }
+        }
+
+        let name = match self.loader.find_symbol(vaddr) {
+            Some(name) => addr2line::demangle_auto(Cow::from(name), None).to_string(),
+            None => lines.last()?.function_name.clone(),
+        };
+
+        Some(Symbol {
+            name,
+            mapping_path: self.path.clone(),
+            rel_pc: vaddr - segment_vaddr + file_offset,
+            lines,
+        })
+    }
+}
+
+// Accepts the raw frame formats bpftrace prints for stacks:
+//   0x7f1c2a8b4d10                       (unresolved address)
+//   0x7f1c2a8b4d10 ([/usr/lib/libc.so.6+0x29d10])
+//   7f1c2a8b4d10 [unknown] (/usr/bin/foo)   (ustack(perf))
+pub fn parse_frame_address(frame: &str) -> Option<FrameAddress> {
+    let mut tokens: Vec<&str> = frame.split_whitespace().collect();
+    if tokens.is_empty() {
+        return None;
+    }
+
+    let first = tokens.remove(0);
+    let address = match first.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        // Only perf formatted frames print addresses without a 0x prefix
+        None if !tokens.is_empty() => u64::from_str_radix(first, 16).ok()?,
+        None => return None,
+    };
+
+    let mut module = None;
+    let mut module_offset = None;
+    if let Some(last) = tokens.last() {
+        if last.starts_with('(') && last.ends_with(')') {
+            let inner = last
+                .trim_start_matches('(')
+                .trim_end_matches(')')
+                .trim_start_matches('[')
+                .trim_end_matches(']');
+            if let Some((path, offset)) = inner.rsplit_once("+0x") {
+                module = Some(path.to_string());
+                module_offset = Some(u64::from_str_radix(offset, 16).ok()?);
+            } else if inner != "unknown" {
+                module = Some(inner.to_string());
+            }
+            tokens.pop();
+        }
+    }
+
+    // Anything left is a symbol bpftrace already resolved
+    if tokens.iter().any(|t| *t != "[unknown]") {
+        return None;
+    }
+
+    Some(FrameAddress {
+        address,
+        module,
+        module_offset,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use object::ObjectSymbol;
+
+    fn parse(frame: &str) -> Option<(u64, Option<String>, Option<u64>)> {
+        parse_frame_address(frame).map(|f| (f.address, f.module, f.module_offset))
+    }
+
+    #[test]
+    fn bare_addresses() {
+        assert_eq!(parse("0x7f1c2a8b4d10"), Some((0x7f1c2a8b4d10, None, None)));
+        assert_eq!(parse("  0x10 "), Some((0x10, None, None)));
+        // Without a 0x prefix it could be a symbol name
+        assert_eq!(parse("deadbeef"), None);
+        assert_eq!(parse("0xnothex"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn addresses_with_a_module() {
+        assert_eq!(
+            parse("0x7f1c2a8b4d10 ([/usr/lib/libc.so.6+0x29d10])"),
+            Some((
+                0x7f1c2a8b4d10,
+                Some("/usr/lib/libc.so.6".to_string()),
+                Some(0x29d10)
+            ))
+        );
+        assert_eq!(parse("0x401136 ([unknown])"), Some((0x401136, None, None)));
+        // ustack(perf)
+        assert_eq!(
+            parse("7f1c2a8b4d10 [unknown] (/usr/bin/foo)"),
+            Some((0x7f1c2a8b4d10, Some("/usr/bin/foo".to_string()), None))
+        );
+    }
+
+    #[test]
+    fn symbolized_frames_are_left_alone() {
+        assert_eq!(parse("main+24"), None);
+        assert_eq!(parse("0x401136 main+24 (/usr/bin/foo)"), None);
+    }
+
+    // A frame of the test binary's main() like bpftrace prints it, and the
+    // offset of main() in the binary
+    pub(crate) fn main_frame() -> (String, u64) {
+        let exe = std::env::current_exe().unwrap();
+        let data = fs::read(&exe).unwrap();
+        let file = object::File::parse(&*data).unwrap();
+        let main = file.symbols().find(|s| s.name() == Ok("main")).unwrap();
+        let offset = file
+            .segments()
+            .find_map(|s| {
+                let (file_offset, _) = s.file_range();
+                let range = s.address()..s.address() + s.size();
+                range
+                    .contains(&main.address())
+                    .then(|| main.address() - s.address() + file_offset)
+            })
+            .unwrap();
+        let exe = exe.to_str().unwrap();
+        (format!("0x7f0000001000 ([{exe}+{offset:#x}])"), offset)
+    }
+
+    #[test]
+    fn rel_pc_is_the_file_offset() {
+        let (frame, offset) = main_frame();
+        let symbol = Symbolizer::new(&[]).symbolize(&frame).unwrap();
+        assert_eq!(symbol.name, "main");
+        let exe = std::env::current_exe().unwrap();
+        assert_eq!(symbol.mapping_path, exe.to_str().unwrap());
+        assert_eq!(symbol.rel_pc, offset);
+    }
+}
@@ -0,0 +1,504 @@
+use serde_json::{json, Map, Value};
+
+// Tuple fields the converter reads as strings even when they look numeric
+// e.g. a thread called "1234"
+const STRING_FIELDS: [&str; 4] = ["name", "type", "thread_name", "unit"];
+
+// Only lines starting a tuple of one of these are read as print() tuples, any
+// other line starting with a paren is printf() output
+const RECORD_TYPES: [&str; 4] = ["track_event", "call_stack", "trigger", "stdout"];
+
+// A tuple still open after this many lines is given up on, e.g. a name with
+// an unbalanced paren, so it can't swallow the rest of the input
+const MAX_TUPLE_LINES: usize = 4096;
+
+// Parses bpftrace's default text output (no `-f json`) into the same values
+// `-f json` would have printed, so both go through the same conversion e.g.
+//   (track_event, (name, page_fault_user), (type, BEGIN), (ts, 123))
+//   @bytes[bash]: 4096
+//   @usecs:
+//   [4, 8)                 3 |@@@@@@@@                                   |
+#[derive(Default)]
+pub struct TextParser {
+    // A print() tuple with a stack spans several lines
+    pending_tuple: String,
+    pending_depth: i64,
+    pending_lines: usize,
+    // (map name, key) and the buckets read so far of a histogram
+    pending_hist: Option<(String, Option<String>, Vec<Value>)>,
+}
+
+pub enum InputParser {
+    Undetected,
+    Json,
+    Text(TextParser),
+}
+
+impl InputParser {
+    // The format is picked from the first non empty line: `-f json` output is
+    // one JSON object per line, anything else is treated as text output
+    pub fn parse_line(&mut self, line: &str) -> Result<Vec<Value>, serde_json::Error> {
+        if let InputParser::Undetected = self {
+            if line.trim().is_empty() {
+                return Ok(Vec::new());
+            }
+            *self = if line.trim_start().starts_with('{') {
+                InputParser::Json
+            } else {
+                InputParser::Text(TextParser::default())
+            };
+        }
+
+        match self {
+            InputParser::Json => Ok(vec![serde_json::from_str(line)?]),
+            InputParser::Text(parser) => Ok(parser.parse_line(line)),
+            InputParser::Undetected => Ok(Vec::new()),
+        }
+    }
+
+    pub fn finish(&mut self) -> Vec<Value> {
+        match self {
+            InputParser::Text(parser) => parser.finish(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl TextParser {
+    pub fn parse_line(&mut self, line: &str) -> Vec<Value> {
+        let line = line.trim_end_matches(['\n', '\r']);
+        let mut out = Vec::new();
+
+        // Checked first as the underflow bucket `(..., 0)` looks like a tuple
+        if self.pending_hist.is_some() {
+            if let Some(bucket) = parse_hist_bucket(line) {
+                self.pending_hist.as_mut().unwrap().2.push(bucket);
+                return out;
+            }
+            out.extend(self.finish_hist());
+        }
+
+        // A new tuple means the pending one was never closed
+        if !self.pending_tuple.is_empty() && is_tuple_start(line) {
+            out.extend(self.abandon_tuple());
+        }
+        if !self.pending_tuple.is_empty() || is_tuple_start(line) {
+            self.pending_tuple.push_str(line);
+            self.pending_tuple.push('\n');
+            self.pending_depth += paren_depth(line);
+            self.pending_lines += 1;
+            if self.pending_depth > 0 {
+                if self.pending_lines >= MAX_TUPLE_LINES {
+                    out.extend(self.abandon_tuple());
+                }
+                return out;
+            }
+            out.push(parse_tuple_output(self.take_tuple().trim_end()));
+            return out;
+        }
+
+        if line.trim().is_empty() {
+            return out;
+        }
+
+        if let Some(probes) = line
+            .strip_prefix("Attaching ")
+            .and_then(|l| l.strip_suffix(" probes..."))
+            .and_then(|n| n.parse::<u64>().ok())
+        {
+            out.push(json!({ "type": "attached_probes", "data": { "probes": probes } }));
+        } else if let Some(events) = line
+            .strip_prefix("Lost ")
+            .and_then(|l| l.strip_suffix(" events"))
+            .and_then(|n| n.parse::<u64>().ok())
+        {
+            out.push(json!({ "type": "lost_events", "data": { "events": events } }));
+        } else if let Some((name, key, value)) = parse_map_line(line) {
+            if value.is_empty() {
+                self.pending_hist = Some((name, key, Vec::new()));
+            } else {
+                out.push(map_output(&name, key, value));
+            }
+        } else {
+            out.push(json!({ "type": "printf", "data": line }));
+        }
+
+        out
+    }
+
+    pub fn finish(&mut self) -> Vec<Value> {
+        let mut out = Vec::new();
+        if !self.pending_tuple.is_empty() {
+            out.push(parse_tuple_output(self.take_tuple().trim_end()));
+        }
+        out.extend(self.finish_hist());
+        out
+    }
+
+    fn take_tuple(&mut self) -> String {
+        self.pending_depth = 0;
+        self.pending_lines = 0;
+        std::mem::take(&mut self.pending_tuple)
+    }
+
+    // The first line of a tuple that never closed is kept as printf() output
+    // and the lines after it are parsed again
+    fn abandon_tuple(&mut self) -> Vec<Value> {
+        let text = self.take_tuple();
+        let mut lines = text.lines();
+        let mut out = Vec::new();
+        if let Some(first_line) = lines.next() {
+            out.push(json!({ "type": "printf", "data": first_line }));
+        }
+        for line in lines {
+            out.extend(self.parse_line(line));
+        }
+        out
+    }
+
+    fn finish_hist(&mut self) -> Option<Value> {
+        let (name, key, buckets) = self.pending_hist.take()?;
+        let buckets = Value::from(buckets);
+        let data = match key {
+            Some(key) => json!({ name: { key: buckets } }),
+            None => json!({ name: buckets }),
+        };
+        Some(json!({ "type": "hist", "data": data }))
+    }
+}
+
+fn is_tuple_start(line: &str) -> bool {
+    let Some(rest) = line.strip_prefix('(') else {
+        return false;
+    };
+    RECORD_TYPES.iter().any(|record_type| {
+        rest.strip_prefix(record_type)
+            .is_some_and(|rest| rest.starts_with(", ") || rest.starts_with(')'))
+    })
+}
+
+fn paren_depth(text: &str) -> i64 {
+    text.chars().fold(0, |depth, c| match c {
+        '(' => depth + 1,
+        ')' => depth - 1,
+        _ => depth,
+    })
+}
+
+fn parse_tuple_output(text: &str) -> Value {
+    let chars: Vec<char> = text.chars().collect();
+    let mut pos = 0;
+    let data = parse_tuple(&chars, &mut pos);
+    json!({ "type": "value", "data": data })
+}
+
+fn parse_tuple(chars: &[char], pos: &mut usize) -> Value {
+    // Skip the opening paren
+    *pos += 1;
+    let mut items = Vec::new();
+    loop {
+        skip_spaces(chars, pos);
+        if *pos >= chars.len() {
+            break;
+        }
+        if chars[*pos] == ')' {
+            *pos += 1;
+            break;
+        }
+
+        if chars[*pos] == '(' {
+            items.push(parse_tuple(chars, pos));
+        } else {
+            let is_string_field = items.len() == 1
+                && items[0]
+                    .as_str()
+                    .is_some_and(|key| STRING_FIELDS.contains(&key));
+            items.push(parse_atom(chars, pos, items.is_empty() || is_string_field));
+        }
+
+        skip_spaces(chars, pos);
+        if *pos < chars.len() && chars[*pos] == ',' {
+            *pos += 1;
+        }
+    }
+    Value::from(items)
+}
+
+fn skip_spaces(chars: &[char], pos: &mut usize) {
+    while *pos < chars.len() && chars[*pos] == ' ' {
+        *pos += 1;
+    }
+}
+
+fn parse_atom(chars: &[char], pos: &mut usize, is_string: bool) -> Value {
+    let start = *pos;
+
+    // Stacks are printed on their own lines, up to the line that closes
+    // the tuple. Keep the newlines so they parse like `-f json` stacks.
+    if chars[start] == '\n' {
+        let mut end = start;
+        for i in start..chars.len() {
+            if chars[i] == '\n' {
+                end = i + 1;
+                let rest: String = chars[end..].iter().collect();
+                if rest.trim_start_matches(' ').starts_with(')') {
+                    break;
+                }
+            }
+        }
+        *pos = end;
+        return Value::from(chars[start..end].iter().collect::<String>());
+    }
+
+    let mut depth = 0;
+    while *pos < chars.len() {
+        match chars[*pos] {
+            '(' => depth += 1,
+            ')' if depth == 0 => break,
+            ')' => depth -= 1,
+            ',' if depth == 0 => break,
+            _ => {}
+        }
+        *pos += 1;
+    }
+    let atom: String = chars[start..*pos].iter().collect();
+    parse_scalar(atom.trim(), is_string)
+}
+
+fn parse_scalar(s: &str, is_string: bool) -> Value {
+    if !is_string {
+        if let Ok(v) = s.parse::<i64>() {
+            return Value::from(v);
+        }
+        if let Ok(v) = s.parse::<u64>() {
+            return Value::from(v);
+        }
+    }
+    Value::from(s)
+}
+
+// `@name: value` or `@name[key, ...]: value`, the value is empty for the
+// first line of a histogram
+fn parse_map_line(line: &str) -> Option<(String, Option<String>, &str)> {
+    if !line.starts_with('@') {
+        return None;
+    }
+    let (name_end, key) = match line.find(['[', ':']) {
+        Some(i) if line[i..].starts_with('[') => {
+            let key_end = i + line[i..].find("]:")?;
+            let key: Vec<&str> = line[i + 1..key_end].split(", ").collect();
+            (i, Some((key.join(","), key_end + 1)))
+        }
+        Some(i) => (i, None),
+        None => return None,
+    };
+    let name = line[..name_end].to_string();
+    let value_start = key.as_ref().map_or(name_end, |k| k.1) + 1;
+    Some((name, key.map(|k| k.0), line[value_start..].trim()))
+}
+
+fn map_output(name: &str, key: Option<String>, value: &str) -> Value {
+    let (out_type, value) = match parse_stats(value) {
+        Some(stats) => ("stats", stats),
+        None => ("map", parse_scalar(value, false)),
+    };
+    let data = match key {
+        Some(key) => json!({ name: { key: value } }),
+        None => json!({ name: value }),
+    };
+    json!({ "type": out_type, "data": data })
+}
+
+// stats() and avg() maps e.g. `count 3, average 40, total 120`
+fn parse_stats(value: &str) -> Option<Value> {
+    let mut stats = Map::new();
+    for field in value.split(", ") {
+        let (name, v) = field.split_once(' ')?;
+        if !matches!(name, "count" | "average" | "total") {
+            return None;
+        }
+        stats.insert(name.to_string(), Value::from(v.parse::<i64>().ok()?));
+    }
+    Some(Value::from(stats))
+}
+
+// hist() and lhist() bucket lines e.g.
+//   (..., 0)               1 |@                                   |
+//   [0]                    2 |@@                                  |
+//   [4, 8)                 3 |@@@                                 |
+//   [1K, 2K)               4 |@@@@                                |
+//   [100, ...)             5 |@@@@@                               |
+fn parse_hist_bucket(line: &str) -> Option<Value> {
+    let line = line.trim_start();
+    if !line.starts_with(['[', '(']) {
+        return None;
+    }
+    let label_end = line.find([']', ')'])?;
+    let label = &line[1..label_end];
+    let count = line[label_end + 1..]
+        .split_whitespace()
+        .next()?
+        .parse::<u64>()
+        .ok()?;
+
+    let mut bucket = Map::new();
+    match label.split_once(", ") {
+        Some((min, max)) => {
+            if min != "..." {
+                bucket.insert("min".to_string(), Value::from(parse_hist_value(min)?));
+            }
+            // The upper bound is exclusive in the text output
+            if max != "..." {
+                bucket.insert("max".to_string(), Value::from(parse_hist_value(max)? - 1));
+            }
+        }
+        None => {
+            let value = parse_hist_value(label)?;
+            bucket.insert("min".to_string(), Value::from(value));
+            bucket.insert("max".to_string(), Value::from(value));
+        }
+    }
+    bucket.insert("count".to_string(), Value::from(count));
+    Some(Value::from(bucket))
+}
+
+// Bucket bounds use power of 1024 suffixes e.g. 4K, 16M
+fn parse_hist_value(s: &str) -> Option<i64> {
+    let (digits, multiplier) = match s.chars().last()? {
+        'K' => (&s[..s.len() - 1], 1 << 10),
+        'M' => (&s[..s.len() - 1], 1 << 20),
+        'G' => (&s[..s.len() - 1], 1 << 30),
+        'T' => (&s[..s.len() - 1], 1 << 40),
+        'P' => (&s[..s.len() - 1], 1 << 50),
+        _ => (s, 1),
+    };
+    Some(digits.parse::<i64>().ok()? * multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Vec<Value> {
+        let mut parser = TextParser::default();
+        let mut out = Vec::new();
+        for line in text.lines() {
+            out.extend(parser.parse_line(line));
+        }
+        out.extend(parser.finish());
+        out
+    }
+
+    #[test]
+    fn tuples() {
+        assert_eq!(
+            parse("(track_event, (name, 1234), (type, BEGIN), (ts, 123), (pid, -1), (cpu, 2))"),
+            [
+                json!({"type": "value", "data": ["track_event", ["name", "1234"], ["type", "BEGIN"],
+                ["ts", 123], ["pid", -1], ["cpu", 2]]})
+            ]
+        );
+        assert_eq!(
+            parse("(trigger, oom_kill)"),
+            [json!({"type": "value", "data": ["trigger", "oom_kill"]})]
+        );
+    }
+
+    #[test]
+    fn multi_line_stacks() {
+        let text = "(call_stack, (ts, 5), (pid, 1), (tid, 2), (kstack, \n        do_sys_open+1\n        __x64_sys_openat+2\n), (ustack, \n        main+4\n))";
+        assert_eq!(
+            parse(text),
+            [
+                json!({"type": "value", "data": ["call_stack", ["ts", 5], ["pid", 1], ["tid", 2],
+                ["kstack", "\n        do_sys_open+1\n        __x64_sys_openat+2\n"],
+                ["ustack", "\n        main+4\n"]]})
+            ]
+        );
+    }
+
+    #[test]
+    fn map_lines() {
+        assert_eq!(
+            parse("@total: 12\n@bytes[bash]: 4096\n@bytes[sshd, 2]: -5"),
+            [
+                json!({"type": "map", "data": {"@total": 12}}),
+                json!({"type": "map", "data": {"@bytes": {"bash": 4096}}}),
+                json!({"type": "map", "data": {"@bytes": {"sshd,2": -5}}}),
+            ]
+        );
+    }
+
+    #[test]
+    fn stats_lines() {
+        assert_eq!(
+            parse("@s[read]: count 3, average 40, total 120"),
+            [
+                json!({"type": "stats", "data": {"@s": {"read": {"count": 3, "average": 40, "total": 120}}}})
+            ]
+        );
+    }
+
+    #[test]
+    fn hist_buckets() {
+        let text = "@usecs:\n\
+            (..., 0)               1 |@                    |\n\
+            [0]                    2 |@@                   |\n\
+            [4, 8)                 3 |@@@                  |\n\
+            [1K, 2K)               4 |@@@@                 |\n\
+            [100, ...)             5 |@@@@@                |\n\
+            \n\
+            @total: 1";
+        assert_eq!(
+            parse(text),
+            [
+                json!({"type": "hist", "data": {"@usecs": [
+                    {"max": -1, "count": 1},
+                    {"min": 0, "max": 0, "count": 2},
+                    {"min": 4, "max": 7, "count": 3},
+                    {"min": 1024, "max": 2047, "count": 4},
+                    {"min": 100, "count": 5},
+                ]}}),
+                json!({"type": "map", "data": {"@total": 1}}),
+            ]
+        );
+    }
+
+    #[test]
+    fn printf_fallback() {
+        assert_eq!(
+            parse("Attaching 2 probes...\nhello\n(pid 12) opened /etc/passwd\nLost 3 events"),
+            [
+                json!({"type": "attached_probes", "data": {"probes": 2}}),
+                json!({"type": "printf", "data": "hello"}),
+                json!({"type": "printf", "data": "(pid 12) opened /etc/passwd"}),
+                json!({"type": "lost_events", "data": {"events": 3}}),
+            ]
+        );
+    }
+
+    #[test]
+    fn unclosed_tuples_are_given_up_on() {
+        // The name's paren is never closed, the next tuple still parses
+        let text = "(track_event, (name, a(), (type, INSTANT))\n@total: 1\n(trigger, oom_kill)";
+        assert_eq!(
+            parse(text),
+            [
+                json!({"type": "printf", "data": "(track_event, (name, a(), (type, INSTANT))"}),
+                json!({"type": "map", "data": {"@total": 1}}),
+                json!({"type": "value", "data": ["trigger", "oom_kill"]}),
+            ]
+        );
+
+        let mut parser = TextParser::default();
+        let mut out = parser.parse_line("(stdout, (");
+        for _ in 0..MAX_TUPLE_LINES {
+            out.extend(parser.parse_line("x"));
+        }
+        assert_eq!(out[0], json!({"type": "printf", "data": "(stdout, ("}));
+        assert!(out[1..]
+            .iter()
+            .all(|v| *v == json!({"type": "printf", "data": "x"})));
+        assert!(parser.pending_tuple.is_empty());
+    }
+}
@@ -1,7 +1,7 @@
 use crate::protos;
 
 use std::collections::HashMap;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{self, BufRead};
 use std::path::Path;
 
@@ -10,28 +10,28 @@ use protos::protos_gen::perfetto_bpftrace::{log_message, track_event};
 pub fn get_log_level(log_level: &str) -> log_message::Priority {
     match log_level {
         "UNSPECIFIED" => {
-            return log_message::Priority::PRIO_UNSPECIFIED;
+            log_message::Priority::PRIO_UNSPECIFIED
         }
         "UNUSED" => {
-            return log_message::Priority::PRIO_UNUSED;
+            log_message::Priority::PRIO_UNUSED
         }
         "VERBOSE" => {
-            return log_message::Priority::PRIO_VERBOSE;
+            log_message::Priority::PRIO_VERBOSE
         }
         "DEBUG" => {
-            return log_message::Priority::PRIO_DEBUG;
+            log_message::Priority::PRIO_DEBUG
         }
         "INFO" => {
-            return log_message::Priority::PRIO_INFO;
+            log_message::Priority::PRIO_INFO
         }
         "WARN" => {
-            return log_message::Priority::PRIO_WARN;
+            log_message::Priority::PRIO_WARN
         }
         "ERROR" => {
-            return log_message::Priority::PRIO_ERROR;
+            log_message::Priority::PRIO_ERROR
         }
         "FATAL" => {
-            return log_message::Priority::PRIO_FATAL;
+            log_message::Priority::PRIO_FATAL
         }
         _ => panic!("Error: Unknown log level {log_level}"),
     }
@@ -41,7 +41,26 @@ pub fn is_event_field(field: &str) -> bool {
     field == "type" || field == "ts" || field == "name" || field == "log" || field == "track" || field == "track_parent"
 }
 
-fn is_valid_event_type(event: &str) -> bool {
+// Counter units add_track_descriptor_counter understands
+pub const COUNTER_UNITS: [&str; 4] = ["unspecified", "count", "sized_bytes", "time_ns"];
+
+pub const LOG_LEVELS: [&str; 8] = [
+    "UNSPECIFIED",
+    "UNUSED",
+    "VERBOSE",
+    "DEBUG",
+    "INFO",
+    "WARN",
+    "ERROR",
+    "FATAL",
+];
+
+// Custom (non thread) tracks have no pid/tid so the Chrome JSON and Firefox
+// exports give them pseudo ids counting up from here, above the largest
+// pid_max (2^22) but still within the 32 bits viewers expect
+pub const TRACK_ID_BASE: u64 = 1 << 30;
+
+pub fn is_valid_event_type(event: &str) -> bool {
     event == "BEGIN" || event == "COUNTER" || event == "END" || event == "INSTANT"
 }
 
@@ -49,7 +68,109 @@ pub fn parse_stack_str(stack1str: &str) -> Vec<String> {
     let mut stack1: Vec<&str> = stack1str.split('\n').collect();
     stack1.remove(0);
     stack1.pop();
-    return stack1.into_iter().map(|x| x.trim().to_string()).collect();
+    stack1.into_iter().map(|x| x.trim().to_string()).collect()
+}
+
+// Reads a single value file e.g. /proc/sys/kernel/hostname
+pub fn read_sys_value(path: &str) -> String {
+    fs::read_to_string(path)
+        .map(|s| s.trim().to_string())
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+// Parses strftime() timestamps into nanoseconds e.g.
+//   2024-05-01 13:45:12, 2024-05-01T13:45:12.123456Z, 2024-05-01 13:45:12+02:00
+//   13:45:12.123456
+// Dates without a UTC offset are taken as UTC. A time without a date is the
+// nanoseconds since midnight.
+pub fn parse_time_str(time_str: &str) -> Option<i64> {
+    let time_str = time_str.trim();
+    let (days, time) = match time_str.get(..10) {
+        Some(date) if date.as_bytes()[4] == b'-' && date.as_bytes()[7] == b'-' => {
+            let year: i64 = date[..4].parse().ok()?;
+            let month: i64 = date[5..7].parse().ok()?;
+            let day: i64 = date[8..10].parse().ok()?;
+            let time = time_str[10..].trim_start_matches(['T', ' ']);
+            (days_from_civil(year, month, day), time)
+        }
+        _ => (0, time_str),
+    };
+
+    let hours: i64 = time.get(..2)?.parse().ok()?;
+    let minutes: i64 = time.get(3..5)?.parse().ok()?;
+    let seconds: i64 = time.get(6..8)?.parse().ok()?;
+    if time.as_bytes()[2] != b':' || time.as_bytes()[5] != b':' {
+        return None;
+    }
+    let mut rest = &time[8..];
+
+    let mut nanos = 0;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let digits = fraction
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(fraction.len());
+        let fraction_ns = format!("{:0<9}", &fraction[..digits.min(9)]);
+        nanos = fraction_ns.parse().ok()?;
+        rest = &fraction[digits..];
+    }
+
+    let utc_offset_secs = match rest {
+        "" | "Z" => 0,
+        _ => {
+            let sign = match rest.as_bytes()[0] {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            let offset = rest[1..].replace(':', "");
+            let offset_hours: i64 = offset.get(..2)?.parse().ok()?;
+            let offset_minutes: i64 = offset.get(2..4)?.parse().ok()?;
+            sign * (offset_hours * 3600 + offset_minutes * 60)
+        }
+    };
+
+    let secs = days * 86400 + hours * 3600 + minutes * 60 + seconds - utc_offset_secs;
+    Some(secs * 1_000_000_000 + nanos)
+}
+
+// Days since 1970-01-01 for a proleptic Gregorian date
+// (http://howardhinnant.github.io/date_algorithms.html#days_from_civil)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let month_index = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// The track a BEGIN or END record is on, for matching them up. Tracks are
+// named by their parent and name, like in name_uuids.
+pub fn slice_track(data: &serde_json::Value) -> String {
+    let get = |key: &str| {
+        data.as_array()
+            .unwrap()
+            .iter()
+            .find(|pair| pair[0] == key)
+            .map(|pair| &pair[1])
+    };
+    match (get("track"), get("pid"), get("tid")) {
+        (Some(track), _, _) => match get("track_parent") {
+            Some(parent) => format!("{}/{}", value_str(parent), value_str(track)),
+            None => value_str(track),
+        },
+        (None, Some(pid), Some(tid)) => format!("{pid}/{tid}"),
+        _ => String::new(),
+    }
+}
+
+// A string value without the JSON quotes, or a number as is
+pub fn value_str(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
 }
 
 pub fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
@@ -63,56 +184,139 @@ where
 pub fn get_track_event_type(event_type: &str) -> track_event::Type {
     match event_type {
         "BEGIN" => {
-            return track_event::Type::TYPE_SLICE_BEGIN;
+            track_event::Type::TYPE_SLICE_BEGIN
         }
         "END" => {
-            return track_event::Type::TYPE_SLICE_END;
+            track_event::Type::TYPE_SLICE_END
         }
         "INSTANT" => {
-            return track_event::Type::TYPE_INSTANT;
+            track_event::Type::TYPE_INSTANT
         }
         "COUNTER" => {
-            return track_event::Type::TYPE_COUNTER;
+            track_event::Type::TYPE_COUNTER
         }
         _ => panic!("Error: Unknown event type {event_type}"),
     }
 }
 
+// `btetto check` reports every violation, the conversion panics on the first
 pub fn validate_track_event(event: &HashMap<&str, serde_json::Value>) {
-    assert!(
-        event.contains_key("name"),
-        "Error: track event must have a name"
-    );
-    assert!(
-        event.contains_key("ts"),
-        "Error: track event must have a ts (timestamp)"
-    );
-    let event_type = event["type"].as_str().unwrap();
-    assert!(
-        event.contains_key("type"),
-        "Error: track must have a valid type"
-    );
-    assert!(
-        is_valid_event_type(event_type),
-        "Error: track must have a valid type. Found {event_type}"
-    );
+    if let Some(violation) = track_event_violations(event).first() {
+        panic!("Error: {violation}");
+    }
+}
+
+pub fn track_event_violations(event: &HashMap<&str, serde_json::Value>) -> Vec<String> {
+    let mut violations = Vec::new();
+    if !event.contains_key("name") {
+        violations.push("track event must have a name".to_string());
+    }
+    if !event.contains_key("ts") {
+        violations.push("track event must have a ts (timestamp)".to_string());
+    }
+    match event.get("type").map(|t| (t, t.as_str())) {
+        // Only known at run time, see check::check_track_event
+        Some((serde_json::Value::Null, _)) => {}
+        None | Some((_, None)) => violations.push("track must have a valid type".to_string()),
+        Some((_, Some(event_type))) if !is_valid_event_type(event_type) => {
+            violations.push(format!("track must have a valid type. Found {event_type}"))
+        }
+        Some(_) => {}
+    }
+    violations
 }
 
 pub fn validate_call_stack_sample(event: &HashMap<&str, serde_json::Value>) {
-    assert!(
-        event.contains_key("ts"),
-        "Error: call stack sample must have a ts (timestamp)"
-    );
-    assert!(
-        event.contains_key("pid"),
-        "Error: call stack sample must have a pid"
-    );
-    assert!(
-        event.contains_key("tid"),
-        "Error: call stack sample must have a tid"
-    );
-    assert!(
-        event.contains_key("ustack") || event.contains_key("kstack"),
-        "Error: call stack sample must have a ustack or a kstack or both"
-    );
+    if let Some(violation) = call_stack_sample_violations(event).first() {
+        panic!("Error: {violation}");
+    }
+}
+
+pub fn call_stack_sample_violations(event: &HashMap<&str, serde_json::Value>) -> Vec<String> {
+    let mut violations = Vec::new();
+    if !event.contains_key("ts") {
+        violations.push("call stack sample must have a ts (timestamp)".to_string());
+    }
+    if !event.contains_key("pid") {
+        violations.push("call stack sample must have a pid".to_string());
+    }
+    if !event.contains_key("tid") {
+        violations.push("call stack sample must have a tid".to_string());
+    }
+    if !event.contains_key("ustack") && !event.contains_key("kstack") {
+        violations.push("call stack sample must have a ustack or a kstack or both".to_string());
+    }
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEC: i64 = 1_000_000_000;
+    // 2024-05-01 13:45:12 UTC
+    const TS: i64 = 1_714_571_112 * SEC;
+
+    #[test]
+    fn dates() {
+        assert_eq!(parse_time_str("2024-05-01 13:45:12"), Some(TS));
+        assert_eq!(parse_time_str("2024-05-01T13:45:12"), Some(TS));
+        assert_eq!(parse_time_str(" 2024-05-01T13:45:12Z\n"), Some(TS));
+        assert_eq!(parse_time_str("1970-01-01 00:00:00"), Some(0));
+    }
+
+    #[test]
+    fn fractional_seconds() {
+        assert_eq!(parse_time_str("2024-05-01 13:45:12.5"), Some(TS + SEC / 2));
+        assert_eq!(
+            parse_time_str("2024-05-01T13:45:12.123456Z"),
+            Some(TS + 123_456_000)
+        );
+        // Digits past nanoseconds are dropped
+        assert_eq!(
+            parse_time_str("13:45:12.1234567891"),
+            Some(49_512 * SEC + 123_456_789)
+        );
+    }
+
+    #[test]
+    fn utc_offsets() {
+        assert_eq!(parse_time_str("2024-05-01 15:45:12+02:00"), Some(TS));
+        assert_eq!(parse_time_str("2024-05-01 15:45:12+0200"), Some(TS));
+        assert_eq!(
+            parse_time_str("2024-05-01T08:15:12.25-05:30"),
+            Some(TS + SEC / 4)
+        );
+        assert_eq!(parse_time_str("2024-05-01 13:45:12 UTC"), None);
+    }
+
+    #[test]
+    fn time_only() {
+        assert_eq!(parse_time_str("13:45:12"), Some(49_512 * SEC));
+        assert_eq!(parse_time_str("00:00:00.000001"), Some(1_000));
+        assert_eq!(parse_time_str("13:45"), None);
+        assert_eq!(parse_time_str("13-45-12"), None);
+        assert_eq!(parse_time_str("1s"), None);
+        assert_eq!(parse_time_str(""), None);
+    }
+
+    #[test]
+    fn leap_years() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(2024, 2, 29), 19_782);
+        assert_eq!(days_from_civil(2024, 3, 1), 19_783);
+        // Divisible by 400 is a leap year, by 100 isn't
+        assert_eq!(days_from_civil(2000, 2, 29), 11_016);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        assert_eq!(
+            days_from_civil(1900, 3, 1) - days_from_civil(1900, 2, 28),
+            1
+        );
+        assert_eq!(days_from_civil(1900, 3, 1), -25_508);
+        assert_eq!(
+            parse_time_str("2024-02-29 00:00:00"),
+            Some(19_782 * 86_400 * SEC)
+        );
+    }
 }